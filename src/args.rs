@@ -1,9 +1,15 @@
-use std::net::Ipv4Addr;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::RangeInclusive;
 use std::str::FromStr;
 
+use dns_lookup::lookup_host;
 use thiserror::Error;
 
+use crate::hosts::{AddressPattern, HostAddr, HostSet};
+use crate::services::{port_by_name, Protocol};
+
 #[derive(Debug, Error)]
 pub enum NetworkParseError {
     #[error("MissingAddress")]
@@ -12,151 +18,502 @@ pub enum NetworkParseError {
     BadIpAddress,
     #[error("BadNetmask")]
     BadNetmask,
-    #[error("InvalidPortNumber")]
-    InvalidPortNumber,
+    #[error("BadRange")]
+    BadRange,
+    #[error("BadZoneId")]
+    BadZoneId,
+    #[error("RangeTooLarge: {0} IPv6 hosts exceed the limit of {}, pass --allow-large-ipv6 to scan them", MAX_IPV6_HOSTS)]
+    RangeTooLarge(u128),
+    #[error("UnresolvedHost: {0}")]
+    UnresolvedHost(String),
+    #[error("MissingPort at position {position}")]
+    MissingPort { position: usize },
+    #[error("InvalidPortNumber: \"{token}\" at position {position}")]
+    InvalidPortNumber { token: String, position: usize },
+    #[error("BadPortRange: \"{token}\" at position {position}")]
+    BadPortRange { token: String, position: usize },
+    #[error("UnknownService: \"{token}\" at position {position}")]
+    UnknownService { token: String, position: usize },
+    #[error("UnknownProtocol: \"{token}\" at position {position}")]
+    UnknownProtocol { token: String, position: usize },
+}
+
+impl NetworkParseError {
+    /// Byte offset and length of the offending token in the port specification
+    pub fn port_span(&self) -> Option<(usize, usize)> {
+        match self {
+            NetworkParseError::MissingPort { position } => Some((*position, 0)),
+            NetworkParseError::InvalidPortNumber { token, position }
+            | NetworkParseError::BadPortRange { token, position }
+            | NetworkParseError::UnknownService { token, position }
+            | NetworkParseError::UnknownProtocol { token, position } => Some((*position, token.len())),
+            _ => None,
+        }
+    }
+
+    fn at_position(self, offset: usize) -> Self {
+        match self {
+            NetworkParseError::MissingPort { .. } => NetworkParseError::MissingPort { position: offset },
+            NetworkParseError::InvalidPortNumber { token, .. } => NetworkParseError::InvalidPortNumber { token, position: offset },
+            NetworkParseError::BadPortRange { token, .. } => NetworkParseError::BadPortRange { token, position: offset },
+            NetworkParseError::UnknownService { token, .. } => NetworkParseError::UnknownService { token, position: offset },
+            NetworkParseError::UnknownProtocol { token, .. } => NetworkParseError::UnknownProtocol { token, position: offset },
+            err => err,
+        }
+    }
+}
+
+/// Largest IPv6 range that is expanded without `allow_large_ipv6`, a /112 prefix.
+pub const MAX_IPV6_HOSTS: u128 = 1 << 16;
+
+/// Options controlling how a host specification is expanded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HostSpecOptions {
+    /// Expand IPv6 ranges with more than `MAX_IPV6_HOSTS` hosts
+    pub allow_large_ipv6: bool,
+    /// Leave out the network and broadcast address of IPv4 networks larger
+    /// than /31, /31 networks have no such addresses (RFC 3021)
+    pub skip_network_broadcast: bool,
 }
 
 /**
-Parse IP addresses with and without subnet masks
+Parse IPv4 and IPv6 addresses and host names with and without subnet masks,
+address ranges and nmap style IPv4 octet ranges.
+Host names are resolved to all of their A and AAAA records, a mask is applied
+to each of the records.
 Examples:
  192.168.1.1
  192.168.1.1/24
+ 2001:db8::/120
+ fe80::1%eth0
+ db01.internal
+ example.lan/28
+ 192.168.1.1-192.168.2.20
+ 192.168.1.10-50
+ 10.0-3.*.1
+ */
+pub fn expand_hosts(host_spec: &str, options: HostSpecOptions) -> Result<HostSet, NetworkParseError> {
+    let (host_spec, scope_id) = zone_from_str(host_spec)?;
+    if let Some((first, last)) = address_range_from_str(&host_spec)? {
+        return expand_range_checked(AddressPattern::Range(first, last), scope_id, options);
+    }
+    if let Some(octets) = octets_from_str(&host_spec)? {
+        return Ok(HostSet::new(AddressPattern::Octets(octets), 0));
+    }
+    if let Some((name, mask)) = host_name_and_netmask_from_str(&host_spec)? {
+        return expand_host_name(name, mask, options);
+    }
+
+    let (addr, mask) = address_and_netmask_from_str(&host_spec)?;
+    expand_range_checked(cidr_range(addr, mask, options), scope_id, options)
+}
+
+/**
+Split a target list into host specifications. Targets are separated by
+whitespace or new lines, `#` starts a comment until the end of the line.
+ */
+pub fn parse_target_list(target_list: &str) -> Vec<&str> {
+    target_list
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(targets, _comment)| targets))
+        .flat_map(str::split_whitespace)
+        .collect()
+}
+
+fn expand_host_name(name: &str, mask: Option<u32>, options: HostSpecOptions) -> Result<HostSet, NetworkParseError> {
+    let addrs = lookup_host(name).map_err(|_err| NetworkParseError::UnresolvedHost(name.to_string()))?;
+    if addrs.is_empty() {
+        return Err(NetworkParseError::UnresolvedHost(name.to_string()));
+    }
+
+    let mut hosts = HostSet::default();
+    for addr in addrs {
+        let mask = match mask {
+            None => address_bits(&addr),
+            Some(mask) if mask <= address_bits(&addr) => mask,
+            Some(_) => return Err(NetworkParseError::BadNetmask),
+        };
+        let mut host_range = expand_range_checked(cidr_range(addr, mask, options), 0, options)?;
+        host_range.insert_name(HostAddr::from(addr), name);
+        hosts.merge(host_range);
+    }
+    Ok(hosts)
+}
+
+fn expand_range_checked(range: AddressPattern, scope_id: u32, options: HostSpecOptions) -> Result<HostSet, NetworkParseError> {
+    let ipv6 = matches!(range, AddressPattern::Range(IpAddr::V6(_), _));
+    let hosts = HostSet::new(range, scope_id);
+    if ipv6 {
+        let host_count = hosts.host_count();
+        if host_count > MAX_IPV6_HOSTS && !options.allow_large_ipv6 {
+            return Err(NetworkParseError::RangeTooLarge(host_count));
+        }
+    }
+    Ok(hosts)
+}
+
+/// Parse a range of full addresses like `192.168.1.1-192.168.2.20`, returns
+/// `None` if the spec is not an address range.
+fn address_range_from_str(host_spec: &str) -> Result<Option<(IpAddr, IpAddr)>, NetworkParseError> {
+    let (first, last) = match host_spec.split_once('-') {
+        None => return Ok(None),
+        Some(range) => range,
+    };
+    let (first, last) = match (IpAddr::from_str(first), IpAddr::from_str(last)) {
+        (Ok(first), Ok(last)) => (first, last),
+        _ => return Ok(None),
+    };
+
+    match (first, last) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) if first <= last => Ok(Some((first, last))),
+        _ => Err(NetworkParseError::BadRange),
+    }
+}
+
+/**
+Parse nmap style IPv4 octet ranges, returns `None` if the spec is not
+an octet range. Each octet is a comma separated list of values and
+ranges, `*` stands for all values, open ranges extend to 0 and 255.
+Examples:
+ 192.168.1.10-50
+ 10.0-3.*.1
+ 192.168.1,3.-9,200-
  */
-pub fn expand_hosts(host_spec: &str) -> Result<HostIpRange, NetworkParseError> {
-    let (addr, mask) = address_and_netmask_from_str(host_spec)?;
-    Ok(expand_hosts_with_netmask(addr, mask))
+fn octets_from_str(host_spec: &str) -> Result<Option<[Vec<u8>; 4]>, NetworkParseError> {
+    let is_octet_char = |c: char| c.is_ascii_digit() || matches!(c, '*' | ',' | '-');
+    let (spec, mask) = match host_spec.split_once('/') {
+        None => (host_spec, None),
+        Some((spec, mask)) => (spec, Some(mask)),
+    };
+    let parts: Vec<&str> = spec.split('.').collect();
+    if parts.len() != 4
+        || !spec.contains(['*', ',', '-'])
+        || !parts.iter().all(|part| part.chars().all(is_octet_char))
+    {
+        return Ok(None);
+    }
+    if mask.is_some() {
+        return Err(NetworkParseError::BadNetmask);
+    }
+
+    let mut octets: [Vec<u8>; 4] = Default::default();
+    for (octet, part) in octets.iter_mut().zip(parts) {
+        *octet = octet_values_from_str(part)?;
+    }
+    Ok(Some(octets))
+}
+
+fn octet_values_from_str(part: &str) -> Result<Vec<u8>, NetworkParseError> {
+    let parse_octet = |value: &str, default: u8| match value {
+        "" => Ok(default),
+        value => value.parse::<u8>().map_err(|_err| NetworkParseError::BadIpAddress),
+    };
+
+    let mut selected = [false; 256];
+    for item in part.split(',') {
+        let (first, last) = match item {
+            "" => return Err(NetworkParseError::BadIpAddress),
+            "*" => (0, 255),
+            item => match item.split_once('-') {
+                None => (parse_octet(item, 0)?, parse_octet(item, 0)?),
+                Some((first, last)) => (parse_octet(first, 0)?, parse_octet(last, 255)?),
+            },
+        };
+        if first > last {
+            return Err(NetworkParseError::BadRange);
+        }
+        selected[usize::from(first)..=usize::from(last)].iter_mut().for_each(|value| *value = true);
+    }
+
+    Ok((0..=255).filter(|&value| selected[usize::from(value)]).collect())
+}
+
+/// Split a spec like `example.lan/28` into host name and mask, returns
+/// `None` if the spec is not a host name.
+fn host_name_and_netmask_from_str(host_spec: &str) -> Result<Option<(&str, Option<u32>)>, NetworkParseError> {
+    let (name, mask) = match host_spec.split_once('/') {
+        None => (host_spec, None),
+        Some((name, mask)) => (name, Some(mask)),
+    };
+    if !is_host_name(name) {
+        return Ok(None);
+    }
+
+    let mask = match mask {
+        None => None,
+        Some(mask) => Some(mask.parse::<u32>().map_err(|_err| NetworkParseError::BadNetmask)?),
+    };
+    Ok(Some((name, mask)))
+}
+
+/// Checks the RFC 1123 host name syntax. Names with a numeric last label
+/// are malformed IPv4 addresses rather than host names.
+fn is_host_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    name.len() <= 253
+        && name.split('.').all(valid_label)
+        && !name.rsplit('.').next().unwrap_or_default().chars().all(|c| c.is_ascii_digit())
 }
 
+/// Split the zone from a spec like `fe80::1%eth0/64`, returning the spec
+/// without the zone and the interface index. Zones are interface names or indexes.
+fn zone_from_str(host_spec: &str) -> Result<(String, u32), NetworkParseError> {
+    let (addr, rest) = match host_spec.split_once('%') {
+        None => return Ok((host_spec.to_string(), 0)),
+        Some(parts) => parts,
+    };
+    let (zone, mask) = match rest.split_once('/') {
+        None => (rest, None),
+        Some((zone, mask)) => (zone, Some(mask)),
+    };
+
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => interface_index(zone).ok_or(NetworkParseError::BadZoneId)?,
+    };
+    if scope_id == 0 || Ipv6Addr::from_str(addr).is_err() {
+        return Err(NetworkParseError::BadZoneId);
+    }
+
+    Ok(match mask {
+        None => (addr.to_string(), scope_id),
+        Some(mask) => (format!("{addr}/{mask}"), scope_id),
+    })
+}
 
-fn address_and_netmask_from_str(host_spec: &str) -> Result<(Ipv4Addr, u32), NetworkParseError> {
+fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+fn address_and_netmask_from_str(host_spec: &str) -> Result<(IpAddr, u32), NetworkParseError> {
     if host_spec.is_empty() {
         return Err(NetworkParseError::MissingAddress);
     }
 
     let (ip, mask) = match host_spec.split_once('/') {
         // Just an IP address
-        None => (host_spec, "32"),
+        None => (host_spec, None),
         // CIDR notation IP/mask
-        Some((ip, mask)) => (ip, mask),
+        Some((ip, mask)) => (ip, Some(mask)),
     };
 
-    Ok((
-        Ipv4Addr::from_str(ip).map_err(|_err| NetworkParseError::BadIpAddress)?,
-        mask.parse::<u32>()
+    let ip = IpAddr::from_str(ip).map_err(|_err| NetworkParseError::BadIpAddress)?;
+    let bits = address_bits(&ip);
+    let mask = match mask {
+        None => bits,
+        Some(mask) => mask.parse::<u32>()
             .map_err(|_err| NetworkParseError::BadNetmask)
-            .and_then(|mask| match mask {
-                0..=32 => Ok(mask),
-                _ => Err(NetworkParseError::BadNetmask),
-            })?,
-    ))
+            .and_then(|mask| if mask <= bits { Ok(mask) } else { Err(NetworkParseError::BadNetmask) })?,
+    };
+
+    Ok((ip, mask))
 }
 
-#[derive(Clone)]
-pub struct HostIpRange {
-    next: u32,
-    last: u32,
+fn address_bits(ip: &IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
 }
 
-impl Iterator for HostIpRange {
-    type Item = Ipv4Addr;
-    fn next(&mut self) -> Option<Ipv4Addr> {
-        if self.next <= self.last {
-            self.next += 1;
-            Some(Ipv4Addr::from(self.next - 1))
-        } else {
-            None
+fn cidr_range(addr: IpAddr, mask: u32, options: HostSpecOptions) -> AddressPattern {
+    match netmask_range(addr, mask) {
+        AddressPattern::Range(IpAddr::V4(network), IpAddr::V4(broadcast)) if mask < 31 && options.skip_network_broadcast => {
+            AddressPattern::Range(
+                Ipv4Addr::from(u32::from(network) + 1).into(),
+                Ipv4Addr::from(u32::from(broadcast) - 1).into(),
+            )
         }
+        range => range,
     }
 }
 
-fn expand_hosts_with_netmask(addr: Ipv4Addr, mask: u32) -> HostIpRange {
-    if mask == 32 {
-        HostIpRange {
-            next: u32::from(addr),
-            last: u32::from(addr),
-        }
-    } else {
-        let ignore_mask = 2_u32.pow(32 - mask) - 1;
-        let netmask = !ignore_mask;
+fn netmask_range(addr: IpAddr, mask: u32) -> AddressPattern {
+    let (value, bits) = match addr {
+        IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+        IpAddr::V6(addr) => (u128::from(addr), 128),
+    };
 
-        HostIpRange {
-            next: (u32::from(addr) & netmask),
-            last: (u32::from(addr) & netmask) + ignore_mask,
-        }
+    let ignore_mask = u128::MAX.checked_shr(128 - (bits - mask)).unwrap_or(0);
+    let netmask = !ignore_mask;
+    let first = value & netmask;
+    let last = first | ignore_mask;
+
+    match addr {
+        IpAddr::V4(_) => AddressPattern::Range(
+            Ipv4Addr::from(first as u32).into(),
+            Ipv4Addr::from(last as u32).into(),
+        ),
+        IpAddr::V6(_) => AddressPattern::Range(Ipv6Addr::from(first).into(), Ipv6Addr::from(last).into()),
     }
 }
 
 fn expand_port_range(x: &str) -> Result<RangeInclusive<u16>, NetworkParseError> {
+    let parse_port = |port: &str| match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(NetworkParseError::InvalidPortNumber { token: x.to_string(), position: 0 }),
+    };
+
     let (from, to) = match x.split_once('-') {
-        None => (
-            x.parse::<u16>()
-                .map_err(|_err| NetworkParseError::InvalidPortNumber)?,
-            x.parse::<u16>()
-                .map_err(|_err| NetworkParseError::InvalidPortNumber)?,
-        ),
+        None if x.is_empty() => return Err(NetworkParseError::MissingPort { position: 0 }),
+        None => (parse_port(x)?, parse_port(x)?),
         Some(("", "")) => (1, 65535),
-        Some((x, y)) => (
-            x.parse::<u16>()
-                .map_err(|_err| NetworkParseError::InvalidPortNumber)?,
-            y.parse::<u16>()
-                .map_err(|_err| NetworkParseError::InvalidPortNumber)?,
-        ),
+        Some((x, y)) => (parse_port(x)?, parse_port(y)?),
     };
+    if from > to {
+        return Err(NetworkParseError::BadPortRange { token: x.to_string(), position: 0 });
+    }
 
     Ok(from..=to)
 }
 
+fn expand_service_name(name: &str, protocol: Protocol) -> Result<RangeInclusive<u16>, NetworkParseError> {
+    let port = port_by_name(name, protocol)
+        .ok_or_else(|| NetworkParseError::UnknownService { token: name.to_string(), position: 0 })?;
+    Ok(port..=port)
+}
+
+fn protocol_from_prefix(prefix: &str) -> Option<Protocol> {
+    match prefix {
+        "T" | "t" => Some(Protocol::Tcp),
+        "U" | "u" => Some(Protocol::Udp),
+        "S" | "s" => Some(Protocol::Sctp),
+        _ => None,
+    }
+}
+
 /**
-Parse comma separated ports and port ranges.
+Parse comma separated ports, port ranges and service names.
+A `T:`, `U:` or `S:` prefix selects TCP, UDP or SCTP for the following
+ports, ports before the first prefix are scanned with each of `protocols`.
+Their service names only have to exist for one of the protocols.
+Ports given more than once are kept at their first position.
+Errors carry the offending token and its byte offset in the port specification.
 Examples:
   22,80,110-120
+  ssh,http,https,3306
+  T:22,80,U:53,161
  */
-pub fn expand_port_list(port_spec: &str) -> Vec<u16> {
-    port_spec
-        .split(',')
-        .flat_map(|p| expand_port_range(p).unwrap())
-        .collect()
+pub fn expand_port_list(port_spec: &str, protocols: &[Protocol]) -> Result<Vec<(Protocol, u16)>, NetworkParseError> {
+    let mut ports = vec![];
+    let mut seen = HashSet::new();
+    let mut protocols = protocols.to_vec();
+    let mut position = 0;
+    for token in port_spec.split(',') {
+        let mut range_position = position;
+        let mut range = token;
+        if let Some((prefix, rest)) = token.split_once(':') {
+            let protocol = protocol_from_prefix(prefix)
+                .ok_or_else(|| NetworkParseError::UnknownProtocol { token: prefix.to_string(), position })?;
+            protocols = vec![protocol];
+            range_position += prefix.len() + 1;
+            range = rest;
+        }
+
+        // Service names like `ms-sql-s` may contain dashes themselves
+        let expanded = if range.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let services: Vec<_> = protocols.iter()
+                .filter_map(|protocol| expand_service_name(range, *protocol).ok().map(|ports| (*protocol, ports)))
+                .collect();
+            match protocols.first() {
+                Some(protocol) if services.is_empty() => expand_service_name(range, *protocol).map(|_| vec![]),
+                _ => Ok(services),
+            }
+        } else {
+            expand_port_range(range).map(|ports| protocols.iter().map(|protocol| (*protocol, ports.clone())).collect())
+        };
+        for (protocol, range) in expanded.map_err(|err| err.at_position(range_position))? {
+            ports.extend(range.map(|port| (protocol, port)).filter(|port| seen.insert(*port)));
+        }
+        position += token.len() + 1;
+    }
+    Ok(ports)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const TCP: &[Protocol] = &[Protocol::Tcp];
+
+    fn tcp(ports: &[u16]) -> Vec<(Protocol, u16)> {
+        ports.iter().map(|port| (Protocol::Tcp, *port)).collect()
+    }
+
     #[test]
     fn test_expand_hosts_with_netmask() {
-        let mut hosts = expand_hosts_with_netmask(Ipv4Addr::new(192, 168, 1, 1), 32);
+        let mut hosts = HostSet::new(netmask_range(Ipv4Addr::new(192, 168, 1, 1).into(), 32), 0).iter();
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
         assert!(hosts.next().is_none());
 
-        let mut hosts = expand_hosts_with_netmask(Ipv4Addr::new(192, 168, 1, 1), 31);
+        let mut hosts = HostSet::new(netmask_range(Ipv4Addr::new(192, 168, 1, 1).into(), 31), 0).iter();
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 0));
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
         assert!(hosts.next().is_none());
 
-        let mut hosts = expand_hosts_with_netmask(Ipv4Addr::new(192, 168, 1, 2), 31);
+        let mut hosts = HostSet::new(netmask_range(Ipv4Addr::new(192, 168, 1, 2).into(), 31), 0).iter();
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 2));
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 3));
         assert!(hosts.next().is_none());
 
-        let mut hosts = expand_hosts_with_netmask(Ipv4Addr::new(192, 168, 1, 1), 30);
+        let mut hosts = HostSet::new(netmask_range(Ipv4Addr::new(192, 168, 1, 1).into(), 30), 0).iter();
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 0));
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 2));
         assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 3));
         assert!(hosts.next().is_none());
 
-        let hosts = expand_hosts_with_netmask(Ipv4Addr::new(192, 168, 1, 1), 24);
+        let hosts = HostSet::new(netmask_range(Ipv4Addr::new(192, 168, 1, 1).into(), 24), 0).iter();
         assert_eq!(hosts.count(), 256);
 
-        let hosts = expand_hosts_with_netmask(Ipv4Addr::new(192, 168, 1, 1), 8);
+        let hosts = HostSet::new(netmask_range(Ipv4Addr::new(192, 168, 1, 1).into(), 8), 0).iter();
         assert_eq!(hosts.count(), 256 * 256 * 256);
     }
 
+    #[test]
+    fn test_expand_hosts_without_network_and_broadcast() {
+        let options = HostSpecOptions { skip_network_broadcast: true, ..HostSpecOptions::default() };
+
+        let hosts = expand_hosts("192.168.1.1/24", options).unwrap();
+        assert_eq!(hosts.host_count(), 254);
+        assert_eq!(hosts.iter().next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts.iter().last().unwrap(), Ipv4Addr::new(192, 168, 1, 254));
+
+        let mut hosts = expand_hosts("192.168.1.1/30", options).unwrap().iter();
+        assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 2));
+        assert!(hosts.next().is_none());
+
+        let mut hosts = expand_hosts("192.168.1.1/31", options).unwrap().iter();
+        assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 0));
+        assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
+        assert!(hosts.next().is_none());
+
+        let mut hosts = expand_hosts("192.168.1.1/32", options).unwrap().iter();
+        assert_eq!(hosts.next().unwrap(), Ipv4Addr::new(192, 168, 1, 1));
+        assert!(hosts.next().is_none());
+
+        assert_eq!(expand_hosts("192.168.1.10-20", options).unwrap().host_count(), 11);
+        assert_eq!(expand_hosts("2001:db8::/120", options).unwrap().host_count(), 256);
+        assert_eq!(expand_hosts("192.168.1.1/24", HostSpecOptions::default()).unwrap().host_count(), 256);
+    }
+
     #[test]
     fn test_parse_address_without_netmask_succeeds() {
         assert_eq!(
             address_and_netmask_from_str("192.168.1.1").unwrap(),
-            (Ipv4Addr::new(192, 168, 1, 1), 32)
+            (IpAddr::from(Ipv4Addr::new(192, 168, 1, 1)), 32)
         );
     }
 
@@ -164,8 +521,232 @@ mod tests {
     fn test_parse_address_with_netmask_succeeds() {
         assert_eq!(
             address_and_netmask_from_str("192.168.1.1/24").unwrap(),
-            (Ipv4Addr::new(192, 168, 1, 1), 24)
+            (IpAddr::from(Ipv4Addr::new(192, 168, 1, 1)), 24)
+        );
+    }
+
+    #[test]
+    fn test_parse_ipv6_address_with_prefix_succeeds() {
+        assert_eq!(
+            address_and_netmask_from_str("2001:db8::1/120").unwrap(),
+            (IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), 120)
         );
+        assert_eq!(
+            address_and_netmask_from_str("2001:db8::1").unwrap().1,
+            128
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadNetmask")]
+    fn test_parse_ipv4_address_with_ipv6_netmask_fails() {
+        address_and_netmask_from_str("192.168.1.1/64").expect("Intended");
+    }
+
+    #[test]
+    fn test_expand_ipv6_hosts_with_netmask() {
+        let mut hosts = HostSet::new(netmask_range(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x1ff).into(), 120), 0).iter();
+        assert_eq!(hosts.host_count(), 256);
+        assert_eq!(hosts.next().unwrap(), Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x100));
+        assert_eq!(hosts.last().unwrap(), Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x1ff));
+
+        let hosts = HostSet::new(netmask_range(Ipv6Addr::UNSPECIFIED.into(), 0), 0).iter();
+        assert_eq!(hosts.host_count(), u128::MAX);
+    }
+
+    #[test]
+    fn test_expand_whole_ipv4_space() {
+        let hosts = HostSet::new(netmask_range(Ipv4Addr::new(10, 0, 0, 1).into(), 0), 0).iter();
+        assert_eq!(hosts.host_count(), 1 << 32);
+    }
+
+    #[test]
+    fn test_expand_hosts_with_zone_id_succeeds() {
+        let hosts = expand_hosts("fe80::1%1", HostSpecOptions::default()).unwrap();
+        let mut hosts = hosts.iter();
+        let host = hosts.next().unwrap();
+        assert_eq!(host.scope_id, 1);
+        assert_eq!(host.to_string(), "fe80::1%1");
+        assert_eq!(host.socket_addr(22).to_string(), "[fe80::1%1]:22");
+        assert!(hosts.next().is_none());
+
+        let hosts = expand_hosts("fe80::%1/126", HostSpecOptions::default()).unwrap();
+        assert!(hosts.iter().map(|host| host.scope_id).eq([1, 1, 1, 1]));
+    }
+
+    #[test]
+    fn test_merge_overlapping_host_sets() {
+        let options = HostSpecOptions::default();
+        let hosts: HostSet = ["10.0.0.0/30", "10.0.0.2", "10.0.0.4/31", "10.0.1.0/31", "::1", "10.0.0.1"]
+            .iter()
+            .map(|spec| expand_hosts(spec, options).unwrap())
+            .collect();
+
+        assert_eq!(hosts.host_count(), 9);
+        assert!(hosts.iter().eq([
+            Ipv4Addr::new(10, 0, 0, 0),
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 3),
+            Ipv4Addr::new(10, 0, 0, 4),
+            Ipv4Addr::new(10, 0, 0, 5),
+            Ipv4Addr::new(10, 0, 1, 0),
+            Ipv4Addr::new(10, 0, 1, 1),
+        ].iter().map(|ip| HostAddr::from(IpAddr::from(*ip))).chain([HostAddr::from(IpAddr::from(Ipv6Addr::LOCALHOST))])));
+    }
+
+    #[test]
+    fn test_expand_octet_ranges_succeeds() {
+        let options = HostSpecOptions::default();
+        let hosts = expand_hosts("192.168.1.10-50", options).unwrap();
+        assert_eq!(hosts.host_count(), 41);
+        assert_eq!(hosts.iter().next().unwrap(), Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(hosts.iter().last().unwrap(), Ipv4Addr::new(192, 168, 1, 50));
+
+        let hosts = expand_hosts("10.0-3.*.1", options).unwrap();
+        assert_eq!(hosts.host_count(), 4 * 256);
+        assert_eq!(hosts.iter().nth(256).unwrap(), Ipv4Addr::new(10, 1, 0, 1));
+
+        let hosts = expand_hosts("10.*.*.*", options).unwrap();
+        assert_eq!(hosts.host_count(), 1 << 24);
+    }
+
+    #[test]
+    fn test_parse_octet_values() {
+        assert_eq!(octet_values_from_str("1,3,5-7").unwrap(), [1, 3, 5, 6, 7]);
+        assert_eq!(octet_values_from_str("253-").unwrap(), [253, 254, 255]);
+        assert_eq!(octet_values_from_str("-2,1").unwrap(), [0, 1, 2]);
+        assert_eq!(octet_values_from_str("*").unwrap().len(), 256);
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadRange")]
+    fn test_parse_reversed_octet_range_fails() {
+        expand_hosts("192.168.1.50-10", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadIpAddress")]
+    fn test_parse_octet_out_of_range_fails() {
+        expand_hosts("192.168.1-300.1", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadNetmask")]
+    fn test_parse_octet_range_with_netmask_fails() {
+        expand_hosts("192.168.1.10-50/24", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    fn test_expand_address_range_succeeds() {
+        let options = HostSpecOptions::default();
+        let hosts = expand_hosts("192.168.1.1-192.168.2.20", options).unwrap();
+        assert_eq!(hosts.host_count(), 255 + 21);
+        assert_eq!(hosts.iter().nth(255).unwrap(), Ipv4Addr::new(192, 168, 2, 0));
+
+        let hosts = expand_hosts("2001:db8::1-2001:db8::ff", options).unwrap();
+        assert_eq!(hosts.host_count(), 255);
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadRange")]
+    fn test_expand_mixed_address_range_fails() {
+        expand_hosts("192.168.1.1-::1", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadRange")]
+    fn test_expand_reversed_address_range_fails() {
+        expand_hosts("192.168.2.1-192.168.1.1", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: RangeTooLarge")]
+    fn test_expand_large_ipv6_address_range_fails() {
+        expand_hosts("2001:db8::-2001:db8::1:0", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    fn test_merge_keeps_zones_apart() {
+        let options = HostSpecOptions::default();
+        let mut hosts = expand_hosts("fe80::1%1", options).unwrap();
+        hosts.merge(expand_hosts("fe80::1", options).unwrap());
+        assert_eq!(hosts.host_count(), 2);
+    }
+
+    #[test]
+    fn test_parse_target_list() {
+        let target_list = "# lab hosts\n10.0.0.0/24 10.0.5.7\n\n  db.lan # primary\n";
+        assert_eq!(parse_target_list(target_list), ["10.0.0.0/24", "10.0.5.7", "db.lan"]);
+    }
+
+    #[test]
+    fn test_host_name_syntax() {
+        assert!(is_host_name("db01.internal"));
+        assert!(is_host_name("localhost"));
+        assert!(is_host_name("example.lan."));
+        assert!(!is_host_name("192.168.1.300"));
+        assert!(!is_host_name("2001:db8::1"));
+        assert!(!is_host_name("-bad.lan"));
+        assert!(!is_host_name("bad..lan"));
+        assert!(!is_host_name(""));
+    }
+
+    #[test]
+    fn test_parse_host_name_with_netmask_succeeds() {
+        assert_eq!(
+            host_name_and_netmask_from_str("example.lan/28").unwrap(),
+            Some(("example.lan", Some(28)))
+        );
+        assert_eq!(host_name_and_netmask_from_str("192.168.1.1/24").unwrap(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadIpAddress")]
+    fn test_parse_bad_ip_address_fails() {
+        expand_hosts("192.168.1.300", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    fn test_expand_host_name_succeeds() {
+        let hosts = expand_hosts("localhost", HostSpecOptions::default()).unwrap();
+        let localhost = HostAddr::from(IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert!(hosts.iter().any(|host| host == localhost));
+        assert_eq!(hosts.name(&localhost), Some("localhost"));
+
+        let hosts = expand_hosts("localhost/30", HostSpecOptions::default()).unwrap();
+        assert!(hosts.iter().any(|host| host == Ipv4Addr::new(127, 0, 0, 3)));
+        assert_eq!(hosts.name(&HostAddr::from(IpAddr::from(Ipv4Addr::new(127, 0, 0, 3)))), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: UnresolvedHost")]
+    fn test_expand_unresolvable_host_name_fails() {
+        expand_hosts("no-such-host.invalid", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadZoneId")]
+    fn test_expand_hosts_with_unknown_zone_fails() {
+        expand_hosts("fe80::1%no-such-interface0", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadZoneId")]
+    fn test_expand_ipv4_hosts_with_zone_fails() {
+        expand_hosts("192.168.1.1%1", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: RangeTooLarge")]
+    fn test_expand_large_ipv6_prefix_fails() {
+        expand_hosts("2001:db8::/64", HostSpecOptions::default()).expect("Intended");
+    }
+
+    #[test]
+    fn test_expand_large_ipv6_prefix_when_allowed_succeeds() {
+        let options = HostSpecOptions { allow_large_ipv6: true, ..HostSpecOptions::default() };
+        assert_eq!(expand_hosts("2001:db8::/64", options).unwrap().host_count(), 1 << 64);
     }
 
     #[test]
@@ -209,11 +790,105 @@ mod tests {
 
     #[test]
     fn test_expand_port_list_with_range_succeeds() {
-        assert_eq!(expand_port_list("1-5"), [1, 2, 3, 4, 5]);
+        assert_eq!(expand_port_list("1-5", TCP).unwrap(), tcp(&[1, 2, 3, 4, 5]));
     }
 
     #[test]
     fn test_expand_port_list_with_enumeration_succeeds() {
-        assert_eq!(expand_port_list("1,2,3,4,5"), [1, 2, 3, 4, 5]);
+        assert_eq!(expand_port_list("1,2,3,4,5", TCP).unwrap(), tcp(&[1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: BadPortRange")]
+    fn test_expand_reversed_port_range_fails() {
+        expand_port_range("90-80").expect("Intended");
+    }
+
+    #[test]
+    #[should_panic(expected = "Intended: InvalidPortNumber")]
+    fn test_expand_port_zero_fails() {
+        expand_port_range("0").expect("Intended");
+    }
+
+    #[test]
+    fn test_expand_port_list_with_service_names_succeeds() {
+        assert_eq!(expand_port_list("ssh,http,https,3306", TCP).unwrap(), tcp(&[22, 80, 443, 3306]));
+        assert_eq!(expand_port_list("ms-sql-s,1-2", TCP).unwrap(), tcp(&[1433, 1, 2]));
+    }
+
+    #[test]
+    fn test_expand_port_list_removes_duplicates() {
+        let ports = expand_port_list("80,80,70-90", TCP).unwrap();
+        assert_eq!(ports.len(), 21);
+        assert_eq!(ports[..2], tcp(&[80, 70]));
+        assert_eq!(expand_port_list("T:53,U:53,53,domain", TCP).unwrap(), [(Protocol::Tcp, 53), (Protocol::Udp, 53)]);
+    }
+
+    #[test]
+    fn test_expand_protocol_qualified_port_list_succeeds() {
+        assert_eq!(
+            expand_port_list("T:22,80,U:53,161-162,S:2905,T:domain", TCP).unwrap(),
+            [
+                (Protocol::Tcp, 22),
+                (Protocol::Tcp, 80),
+                (Protocol::Udp, 53),
+                (Protocol::Udp, 161),
+                (Protocol::Udp, 162),
+                (Protocol::Sctp, 2905),
+                (Protocol::Tcp, 53),
+            ]
+        );
+        assert_eq!(expand_port_list("u:snmp", TCP).unwrap(), [(Protocol::Udp, 161)]);
+    }
+
+    #[test]
+    fn test_expand_protocol_qualified_port_list_errors_carry_position() {
+        let err = expand_port_list("T:22,X:80", TCP).unwrap_err();
+        assert_eq!(err.to_string(), "UnknownProtocol: \"X\" at position 5");
+
+        let err = expand_port_list("T:22,U:70-60", TCP).unwrap_err();
+        assert_eq!(err.port_span(), Some((7, 5)));
+
+        let err = expand_port_list("U:", TCP).unwrap_err();
+        assert_eq!(err.to_string(), "MissingPort at position 2");
+    }
+
+    #[test]
+    fn test_expand_port_list_with_unknown_service_fails() {
+        let err = expand_port_list("ssh,gopherx", TCP).unwrap_err();
+        assert_eq!(err.to_string(), "UnknownService: \"gopherx\" at position 4");
+    }
+
+    #[test]
+    fn test_expand_port_list_errors_carry_position() {
+        let err = expand_port_list("22,90-80", TCP).unwrap_err();
+        assert_eq!(err.to_string(), "BadPortRange: \"90-80\" at position 3");
+        assert_eq!(err.port_span(), Some((3, 5)));
+
+        let err = expand_port_list("22,,80", TCP).unwrap_err();
+        assert_eq!(err.to_string(), "MissingPort at position 3");
+
+        let err = expand_port_list("22,80,", TCP).unwrap_err();
+        assert_eq!(err.port_span(), Some((6, 0)));
+
+        let err = expand_port_list("22,8o", TCP).unwrap_err();
+        assert_eq!(err.to_string(), "InvalidPortNumber: \"8o\" at position 3");
+    }
+
+    #[test]
+    fn test_expand_port_list_for_several_protocols_succeeds() {
+        let both = [Protocol::Tcp, Protocol::Udp];
+        assert_eq!(
+            expand_port_list("53,ssh,snmp,T:80", &both).unwrap(),
+            [(Protocol::Tcp, 53), (Protocol::Udp, 53), (Protocol::Tcp, 22), (Protocol::Udp, 161), (Protocol::Tcp, 80)]
+        );
+        assert_eq!(expand_port_list("1-2", &[Protocol::Udp]).unwrap(), [(Protocol::Udp, 1), (Protocol::Udp, 2)]);
+
+        let err = expand_port_list("53,gopherx", &both).unwrap_err();
+        assert_eq!(err.to_string(), "UnknownService: \"gopherx\" at position 3");
+    }
+    #[test]
+    fn test_expand_port_list_without_protocols_is_empty() {
+        assert_eq!(expand_port_list("ssh,80,T:22", &[]).unwrap(), [(Protocol::Tcp, 22)]);
     }
 }
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockAddr, Socket};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::mac::MacAddr;
+use crate::raw::{is_transient, raw_socket, recv_from, send_to};
+
+const ETHERNET_BROADCAST: [u8; 6] = [0xff; 6];
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// Ethernet header and ARP message for IPv4 over Ethernet
+const ARP_PACKET_LEN: usize = 14 + 28;
+
+/// ARP requests sent to a host within the timeout, ARP has no retransmission of its own
+const ARP_ATTEMPTS: u32 = 2;
+
+/// ARP requests waiting for a reply, by the requested address
+type Pending = Arc<Mutex<HashMap<Ipv4Addr, oneshot::Sender<MacAddr>>>>;
+
+/// IPv4 network of a local Ethernet interface
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalNetwork {
+    pub index: u32,
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl LocalNetwork {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let netmask = u32::from(self.netmask);
+        u32::from(ip) & netmask == u32::from(self.ip) & netmask && ip != self.ip
+    }
+}
+
+/// Ethernet interfaces with ARP and their IPv4 networks
+pub fn local_networks() -> io::Result<Vec<LocalNetwork>> {
+    let mut interfaces = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut macs = HashMap::new();
+    let mut addresses = vec![];
+    let mut interface = interfaces;
+    // SAFETY: getifaddrs returned a valid list, it is freed after the loop
+    while let Some(entry) = unsafe { interface.as_ref() } {
+        interface = entry.ifa_next;
+        let flags = entry.ifa_flags as i32;
+        if entry.ifa_addr.is_null() || flags & libc::IFF_UP == 0 || flags & (libc::IFF_LOOPBACK | libc::IFF_NOARP) != 0 {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) }.to_owned();
+        match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_PACKET => {
+                let link = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_ll) };
+                if link.sll_hatype == libc::ARPHRD_ETHER && link.sll_halen == 6 {
+                    let mut mac = [0; 6];
+                    mac.copy_from_slice(&link.sll_addr[..6]);
+                    macs.insert(name, (link.sll_ifindex as u32, MacAddr(mac)));
+                }
+            }
+            libc::AF_INET if !entry.ifa_netmask.is_null() => {
+                let ip = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                let netmask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(ip.sin_addr.s_addr));
+                let netmask = Ipv4Addr::from(u32::from_be(netmask.sin_addr.s_addr));
+                addresses.push((name, ip, netmask));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+
+    Ok(addresses
+        .into_iter()
+        .filter_map(|(name, ip, netmask)| {
+            let (index, mac) = *macs.get(&name)?;
+            Some(LocalNetwork { index, mac, ip, netmask })
+        })
+        .collect())
+}
+
+/// Resolves addresses on directly attached Ethernet networks with ARP
+/// requests from a packet socket. Hosts have to answer ARP requests to be
+/// reachable at all, so ARP finds hosts that drop every other probe.
+pub struct ArpScanner {
+    socket: Arc<AsyncFd<Socket>>,
+    networks: Vec<LocalNetwork>,
+    pending: Pending,
+    listener: JoinHandle<()>,
+}
+
+impl ArpScanner {
+    /// Open the packet socket, fails without CAP_NET_RAW.
+    /// Must be called from within the tokio runtime.
+    pub fn new() -> io::Result<Self> {
+        let socket = Arc::new(raw_socket(Domain::PACKET, Protocol::from(i32::from(ETHERTYPE_ARP.to_be())))?);
+        let networks = local_networks()?;
+        let pending = Pending::default();
+        let listener = tokio::spawn(listen(socket.clone(), pending.clone()));
+        Ok(ArpScanner { socket, networks, pending, listener })
+    }
+
+    /// Local network the host is directly attached to
+    pub fn network_of(&self, ip: IpAddr) -> Option<&LocalNetwork> {
+        match ip {
+            IpAddr::V4(ip) => self.networks.iter().find(|network| network.contains(ip)),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    /// MAC address of a host on a local network, `None` without a reply.
+    /// The request is repeated once within the timeout in case it got lost.
+    pub async fn resolve(&self, ip: Ipv4Addr, timeout: Duration) -> Option<MacAddr> {
+        let network = self.network_of(ip.into())?;
+        let (sender, mut reply) = oneshot::channel();
+        self.pending.lock().unwrap().insert(ip, sender);
+
+        let packet = arp_request(network.mac, network.ip, ip);
+        let mut mac = None;
+        for _ in 0..ARP_ATTEMPTS {
+            if send_to(&self.socket, &packet, &link_addr(network.index)).await.is_err() {
+                break;
+            }
+            if let Ok(reply) = tokio::time::timeout(timeout / ARP_ATTEMPTS, &mut reply).await {
+                mac = reply.ok();
+                break;
+            }
+        }
+        self.pending.lock().unwrap().remove(&ip);
+        mac
+    }
+}
+
+impl Drop for ArpScanner {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// Ethernet broadcast on the interface
+fn link_addr(index: u32) -> SockAddr {
+    // SAFETY: sockaddr_storage is large enough for and aligned like sockaddr_ll
+    unsafe {
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        let link = &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_ll);
+        link.sll_family = libc::AF_PACKET as u16;
+        link.sll_protocol = ETHERTYPE_ARP.to_be();
+        link.sll_ifindex = index as i32;
+        link.sll_halen = 6;
+        link.sll_addr[..6].copy_from_slice(&ETHERNET_BROADCAST);
+        SockAddr::new(storage, std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+    }
+}
+
+fn arp_request(source_mac: MacAddr, source_ip: Ipv4Addr, target_ip: Ipv4Addr) -> [u8; ARP_PACKET_LEN] {
+    let mut packet = [0; ARP_PACKET_LEN];
+    packet[0..6].copy_from_slice(&ETHERNET_BROADCAST);
+    packet[6..12].copy_from_slice(&source_mac.0);
+    packet[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    // Ethernet hardware and IPv4 protocol addresses
+    packet[14..20].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+    packet[20..22].copy_from_slice(&ARP_REQUEST.to_be_bytes());
+    packet[22..28].copy_from_slice(&source_mac.0);
+    packet[28..32].copy_from_slice(&source_ip.octets());
+    packet[38..42].copy_from_slice(&target_ip.octets());
+    packet
+}
+
+/// Sender of an ARP reply
+fn parse_arp_reply(packet: &[u8]) -> Option<(Ipv4Addr, MacAddr)> {
+    if packet.len() < ARP_PACKET_LEN
+        || packet[12..14] != ETHERTYPE_ARP.to_be_bytes()
+        || packet[14..20] != [0, 1, 0x08, 0x00, 6, 4]
+        || packet[20..22] != ARP_REPLY.to_be_bytes()
+    {
+        return None;
+    }
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&packet[22..28]);
+    Some((Ipv4Addr::new(packet[28], packet[29], packet[30], packet[31]), MacAddr(mac)))
+}
+
+async fn listen(socket: Arc<AsyncFd<Socket>>, pending: Pending) {
+    let mut buffer = vec![MaybeUninit::<u8>::uninit(); 1500];
+    loop {
+        let packet = match recv_from(&socket, &mut buffer).await {
+            Ok((packet, _from)) => packet,
+            Err(err) if is_transient(&err) => continue,
+            // Dropping the senders ends the waiting requests without a reply
+            Err(_) => return pending.lock().unwrap().clear(),
+        };
+        if let Some((ip, mac)) = parse_arp_reply(packet) {
+            if let Some(sender) = pending.lock().unwrap().remove(&ip) {
+                let _ = sender.send(mac);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_network_contains() {
+        let network = LocalNetwork {
+            index: 2,
+            mac: MacAddr([2, 0, 0, 0, 0, 1]),
+            ip: Ipv4Addr::new(192, 168, 1, 10),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+        };
+        assert!(network.contains(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(!network.contains(Ipv4Addr::new(192, 168, 1, 10)));
+        assert!(!network.contains(Ipv4Addr::new(192, 168, 2, 1)));
+    }
+
+    #[test]
+    fn test_parse_arp_reply() {
+        let mac = MacAddr([2, 0, 0, 0, 0, 1]);
+        let mut packet = arp_request(mac, Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(parse_arp_reply(&packet), None);
+
+        packet[20..22].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        assert_eq!(parse_arp_reply(&packet), Some((Ipv4Addr::new(10, 0, 0, 2), mac)));
+        assert_eq!(parse_arp_reply(&packet[..30]), None);
+    }
+
+    /// Virtual Ethernet link to a host in its own network namespace,
+    /// removed again when dropped
+    struct VethPeer {
+        namespace: &'static str,
+        link: &'static str,
+    }
+
+    impl VethPeer {
+        fn new(namespace: &'static str, link: &'static str, peer_mac: &str) -> Self {
+            let ip = |args: &str| {
+                let status = std::process::Command::new("ip").args(args.split_whitespace()).status().expect("ip");
+                assert!(status.success(), "ip {}", args);
+            };
+            let peer = VethPeer { namespace, link };
+            ip(&format!("netns add {namespace}"));
+            ip(&format!("link add {link} type veth peer name {link}p"));
+            ip(&format!("link set {link}p netns {namespace} address {peer_mac}"));
+            ip(&format!("addr add 10.213.0.1/24 dev {link}"));
+            ip(&format!("link set {link} up"));
+            ip(&format!("-n {namespace} addr add 10.213.0.2/24 dev {link}p"));
+            ip(&format!("-n {namespace} link set {link}p up"));
+            peer
+        }
+    }
+
+    impl Drop for VethPeer {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("ip").args(["link", "del", self.link]).status();
+            let _ = std::process::Command::new("ip").args(["netns", "del", self.namespace]).status();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs root and iproute2, run with `cargo test -- --ignored`"]
+    async fn test_resolve_veth_peer_in_namespace() {
+        let _peer = VethPeer::new("rmap-arp-test", "rmaparp0", "00:16:3e:00:00:02");
+        // The link needs a moment to come up
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let scanner = ArpScanner::new().unwrap();
+        assert!(scanner.network_of(Ipv4Addr::new(10, 213, 0, 2).into()).is_some());
+        let mac = scanner.resolve(Ipv4Addr::new(10, 213, 0, 2), Duration::from_secs(2)).await;
+        assert_eq!(mac, Some(MacAddr([0x00, 0x16, 0x3e, 0, 0, 2])));
+        assert_eq!(mac.unwrap().vendor(), Some("Xensource"));
+        assert_eq!(scanner.resolve(Ipv4Addr::new(10, 213, 0, 3), Duration::from_millis(500)).await, None);
+    }
+}
@@ -0,0 +1,97 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use crate::hosts::HostName;
+use crate::output::{Output, RunStats};
+use crate::scan::HostResult;
+use crate::services::service_name;
+
+const HEADER: &str = "address,hostname,mac,protocol,port,service,state,reason";
+
+/// One row per port of each host, after a header row. Hosts without port
+/// scan get one row with empty port columns.
+pub struct CsvWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "{HEADER}")?;
+        Ok(CsvWriter { writer })
+    }
+}
+
+impl<W: Write> Output for CsvWriter<W> {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()> {
+        let host = format!(
+            "{},{},{}",
+            result.host,
+            quote(name.map_or("", HostName::name)),
+            result.up.mac.map(|mac| mac.to_string()).unwrap_or_default(),
+        );
+        match &result.port_states {
+            Some(port_states) => {
+                for ((protocol, port), port_status) in port_states {
+                    let service = service_name(*port, *protocol).unwrap_or("");
+                    writeln!(
+                        self.writer,
+                        "{host},{protocol},{port},{},{},{}",
+                        quote(service),
+                        quote(port_status.state.name()),
+                        port_status.reason,
+                    )?;
+                }
+                Ok(())
+            }
+            None => writeln!(self.writer, "{host},,,,,"),
+        }
+    }
+
+    fn finish(mut self: Box<Self>, _stats: &RunStats) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Quote a field with separators, quotes or line breaks, as in RFC 4180
+fn quote(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::fixtures::{host_result, run_stats};
+    use crate::scan::{PortState, PortStatus, Reason};
+    use crate::services::Protocol;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("ssh"), "ssh");
+        assert_eq!(quote("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn test_csv_rows() {
+        let port_states = vec![
+            ((Protocol::Udp, 53), PortStatus::new(PortState::OpenFiltered, Reason::NoResponse)),
+            ((Protocol::Tcp, 22), PortStatus::new(PortState::Open, Reason::SynAck)),
+        ];
+        let result = HostResult { port_states: Some(port_states.into_iter().collect()), ..host_result() };
+
+        let mut output = vec![];
+        let mut csv = CsvWriter::new(&mut output).unwrap();
+        csv.write_host(&result, Some(&HostName::Target("a,b".to_string()))).unwrap();
+        csv.write_host(&HostResult { port_states: None, ..result }, None).unwrap();
+        Box::new(csv).finish(&run_stats()).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+            address,hostname,mac,protocol,port,service,state,reason\n\
+            192.0.2.1,\"a,b\",,tcp,22,ssh,open,syn-ack\n\
+            192.0.2.1,\"a,b\",,udp,53,domain,open|filtered,no-response\n\
+            192.0.2.1,,,,,,,\n");
+    }
+}
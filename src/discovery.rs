@@ -0,0 +1,255 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+
+use crate::args::expand_port_list;
+use crate::arp::ArpScanner;
+use crate::hosts::HostAddr;
+use crate::icmp::{IcmpPinger, IcmpProbe};
+use crate::mac::MacAddr;
+use crate::raw::Route;
+use crate::scan::{connect_probe, PortState, ProbeLimits, Reason, TcpScan};
+use crate::services::Protocol;
+
+/// Ports of the TCP pings without a port list, like nmap
+const DEFAULT_SYN_PING_PORT: u16 = 443;
+const DEFAULT_ACK_PING_PORT: u16 = 80;
+
+/// Host discovery probe types, given like nmap's `-P` options
+#[derive(Clone, Debug, PartialEq)]
+pub enum PingType {
+    /// `-Pn`, scan the ports of all hosts, only hosts with an open or closed port are reported
+    Skip,
+    /// `-PE`, ICMP echo request
+    IcmpEcho,
+    /// `-PP`, ICMP timestamp request, often passes filters for echo requests
+    IcmpTimestamp,
+    /// `-PM`, ICMP address mask request
+    IcmpAddressMask,
+    /// `-PS<ports>`, TCP SYN, any answer shows the host is up
+    TcpSyn(Vec<u16>),
+    /// `-PA<ports>`, TCP ACK, passes firewalls that only block new connections
+    TcpAck(Vec<u16>),
+    /// `-PR`, ARP request to hosts on directly attached Ethernet networks
+    Arp,
+}
+
+impl PingType {
+    /// The probes used without `-P` options
+    pub fn defaults() -> Vec<PingType> {
+        vec![
+            PingType::IcmpEcho,
+            PingType::TcpSyn(vec![DEFAULT_SYN_PING_PORT]),
+            PingType::TcpAck(vec![DEFAULT_ACK_PING_PORT]),
+            PingType::IcmpTimestamp,
+            PingType::Arp,
+        ]
+    }
+
+    fn icmp_probe(&self) -> Option<IcmpProbe> {
+        match self {
+            PingType::IcmpEcho => Some(IcmpProbe::Echo),
+            PingType::IcmpTimestamp => Some(IcmpProbe::Timestamp),
+            PingType::IcmpAddressMask => Some(IcmpProbe::AddressMask),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for PingType {
+    type Err = String;
+    fn from_str(ping_type: &str) -> Result<Self, Self::Err> {
+        let tcp_ports = |port_spec: &str, default_port| {
+            if port_spec.is_empty() {
+                return Ok(vec![default_port]);
+            }
+            expand_port_list(port_spec, &[Protocol::Tcp])
+                .map(|ports| ports.into_iter().map(|(_, port)| port).collect())
+                .map_err(|err| err.to_string())
+        };
+        let mut chars = ping_type.chars();
+        match (chars.next(), chars.as_str()) {
+            (Some('n'), "") => Ok(PingType::Skip),
+            (Some('E'), "") => Ok(PingType::IcmpEcho),
+            (Some('P'), "") => Ok(PingType::IcmpTimestamp),
+            (Some('M'), "") => Ok(PingType::IcmpAddressMask),
+            (Some('S'), ports) => tcp_ports(ports, DEFAULT_SYN_PING_PORT).map(PingType::TcpSyn),
+            (Some('A'), ports) => tcp_ports(ports, DEFAULT_ACK_PING_PORT).map(PingType::TcpAck),
+            (Some('R'), "") => Ok(PingType::Arp),
+            _ => Err(format!("unknown ping type \"{ping_type}\", expected n, E, P, M, S[ports], A[ports] or R")),
+        }
+    }
+}
+
+/// What host discovery learned about a host that is up
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HostUp {
+    /// Hardware address of a host on a directly attached network
+    pub mac: Option<MacAddr>,
+    /// Round trip time of the first answered probe
+    pub rtt: Option<Duration>,
+    /// Reply to that probe, `None` without host discovery
+    pub reason: Option<Reason>,
+}
+
+/// Finds the hosts that are up before their ports are scanned. Probes
+/// without the raw sockets they need are left out, TCP pings then fall
+/// back to connecting to the ports. The probes obey the limits of the port
+/// scan.
+pub struct Discovery {
+    ping_types: Vec<PingType>,
+    tcp_scan: TcpScan,
+    icmp: Option<Arc<IcmpPinger>>,
+    arp: Option<Arc<ArpScanner>>,
+    limits: Arc<ProbeLimits>,
+}
+
+impl Discovery {
+    pub fn new(
+        ping_types: Vec<PingType>,
+        tcp_scan: TcpScan,
+        icmp: Option<Arc<IcmpPinger>>,
+        arp: Option<Arc<ArpScanner>>,
+        limits: Arc<ProbeLimits>,
+    ) -> Self {
+        Discovery { ping_types, tcp_scan, icmp, arp, limits }
+    }
+
+    /// Hosts on a directly attached network are only asked with ARP, other
+    /// hosts are up when any of the probes gets an answer. `None` for hosts
+    /// that are down.
+    pub async fn discover(&self, host: HostAddr, timeout: Duration) -> Option<HostUp> {
+        let host_rate = self.limits.host_rate_limiter();
+        let host_rate = host_rate.as_ref();
+        if let (Some(arp), IpAddr::V4(ip)) = (&self.arp, host.ip) {
+            if self.ping_types.contains(&PingType::Arp) && arp.network_of(host.ip).is_some() {
+                let _permit = self.limits.acquire(host_rate).await;
+                let sent = Instant::now();
+                return arp.resolve(ip, timeout).await.map(|mac| HostUp {
+                    mac: Some(mac),
+                    rtt: Some(sent.elapsed()),
+                    reason: Some(Reason::ArpResponse),
+                });
+            }
+        }
+
+        let route = &Route::new(host);
+        // The reply to each probe with its round trip time, from after the
+        // probe got past the limits
+        let mut probes: FuturesUnordered<BoxFuture<Option<(Duration, Reason)>>> = FuturesUnordered::new();
+        for ping_type in &self.ping_types {
+            match (ping_type, &self.tcp_scan) {
+                (PingType::IcmpEcho | PingType::IcmpTimestamp | PingType::IcmpAddressMask, _) => {
+                    match (&self.icmp, ping_type.icmp_probe()) {
+                        (Some(icmp), Some(icmp_probe)) if icmp.supports(host, icmp_probe) => {
+                            probes.push(async move {
+                                let _permit = self.limits.acquire(host_rate).await;
+                                let reason = match icmp_probe {
+                                    IcmpProbe::Echo => Reason::EchoReply,
+                                    IcmpProbe::Timestamp => Reason::TimestampReply,
+                                    IcmpProbe::AddressMask => Reason::AddressMaskReply,
+                                };
+                                icmp.probe(host, icmp_probe, timeout).await.map(|rtt| (rtt, reason))
+                            }.boxed());
+                        }
+                        _ => {}
+                    }
+                }
+                (PingType::TcpSyn(ports), TcpScan::Syn(scanner)) => probes.extend(ports.iter().map(|port| {
+                    async move {
+                        let _permit = self.limits.acquire(host_rate).await;
+                        let sent = Instant::now();
+                        let port_status = scanner.probe(route, *port, timeout).await;
+                        matches!(port_status.state, PortState::Open | PortState::Closed).then(|| (sent.elapsed(), port_status.reason))
+                    }.boxed()
+                })),
+                (PingType::TcpAck(ports), TcpScan::Syn(scanner)) => probes.extend(ports.iter().map(|port| {
+                    async move {
+                        let _permit = self.limits.acquire(host_rate).await;
+                        let sent = Instant::now();
+                        let port_status = scanner.ack_probe(route, *port, timeout).await;
+                        (port_status.state == PortState::Closed).then(|| (sent.elapsed(), port_status.reason))
+                    }.boxed()
+                })),
+                (PingType::TcpSyn(ports) | PingType::TcpAck(ports), TcpScan::Connect) => probes.extend(ports.iter().map(|port| {
+                    async move {
+                        let _permit = self.limits.acquire(host_rate).await;
+                        let sent = Instant::now();
+                        let port_status = connect_probe(host, *port, timeout).await;
+                        matches!(port_status.state, PortState::Open | PortState::Closed).then(|| (sent.elapsed(), port_status.reason))
+                    }.boxed()
+                })),
+                (PingType::Skip, _) | (PingType::Arp, _) => {}
+            }
+        }
+
+        while let Some(reply) = probes.next().await {
+            if let Some((rtt, reason)) = reply {
+                return Some(HostUp { mac: None, rtt: Some(rtt), reason: Some(reason) });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::TimingPolicy;
+
+    #[test]
+    fn test_parse_ping_types() {
+        assert_eq!("n".parse(), Ok(PingType::Skip));
+        assert_eq!("E".parse(), Ok(PingType::IcmpEcho));
+        assert_eq!("P".parse(), Ok(PingType::IcmpTimestamp));
+        assert_eq!("M".parse(), Ok(PingType::IcmpAddressMask));
+        assert_eq!("S".parse(), Ok(PingType::TcpSyn(vec![443])));
+        assert_eq!("S22,80-81".parse(), Ok(PingType::TcpSyn(vec![22, 80, 81])));
+        assert_eq!("Ahttp".parse(), Ok(PingType::TcpAck(vec![80])));
+        assert_eq!("R".parse(), Ok(PingType::Arp));
+        assert!("X".parse::<PingType>().is_err());
+        assert!("S0".parse::<PingType>().is_err());
+        assert!("".parse::<PingType>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_ping_finds_localhost() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let limits = Arc::new(ProbeLimits::new(&TimingPolicy::default()));
+        let discovery = Discovery::new(vec![PingType::TcpSyn(vec![port])], TcpScan::Connect, None, None, limits.clone());
+        let localhost = HostAddr::from(IpAddr::from([127, 0, 0, 1]));
+        let up = discovery.discover(localhost, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(up.mac, None);
+        assert_eq!(up.reason, Some(Reason::SynAck));
+        assert!(up.rtt.unwrap() < Duration::from_secs(1));
+
+        let discovery = Discovery::new(vec![PingType::IcmpEcho], TcpScan::Connect, None, None, limits.clone());
+        assert_eq!(discovery.discover(localhost, Duration::from_secs(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_rtt_leaves_out_waiting_for_limits() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let timing = TimingPolicy { max_parallelism: 1, ..TimingPolicy::default() };
+        let limits = Arc::new(ProbeLimits::new(&timing));
+        let discovery = Discovery::new(vec![PingType::TcpSyn(vec![port])], TcpScan::Connect, None, None, limits.clone());
+        let localhost = HostAddr::from(IpAddr::from([127, 0, 0, 1]));
+
+        let permit = limits.acquire(None).await;
+        let waited = Instant::now();
+        let (up, ()) = tokio::join!(discovery.discover(localhost, Duration::from_secs(5)), async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(permit);
+        });
+        assert!(waited.elapsed() >= Duration::from_secs(1));
+        assert!(up.unwrap().rtt.unwrap() < Duration::from_millis(500));
+    }
+}
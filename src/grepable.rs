@@ -0,0 +1,95 @@
+use std::io::{self, Write};
+
+use crate::hosts::HostName;
+use crate::output::{time_string, Output, RunStats, ScanInfo};
+use crate::scan::HostResult;
+use crate::services::service_name;
+
+/// nmap's grepable output, lines per host with fields separated by tabs,
+/// like `Host: 192.0.2.1 (name)`, `Ports: 22/open/tcp//ssh///, 80/closed/tcp//http///`
+pub struct GrepableWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> GrepableWriter<W> {
+    pub fn new(mut writer: W, scan: &ScanInfo) -> io::Result<Self> {
+        writeln!(
+            writer,
+            "# rmap {} scan initiated {} as: {}",
+            env!("CARGO_PKG_VERSION"),
+            time_string(scan.start_time),
+            scan.arguments.join(" "),
+        )?;
+        Ok(GrepableWriter { writer })
+    }
+}
+
+impl<W: Write> Output for GrepableWriter<W> {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()> {
+        let host = format!("Host: {} ({})", result.host, name.map_or("", HostName::name));
+        writeln!(self.writer, "{host}\tStatus: Up")?;
+        if let Some(port_states) = &result.port_states {
+            // port/state/protocol/owner/service/rpc info/version/
+            let ports: Vec<String> = port_states.iter().map(|((protocol, port), port_status)| {
+                let service = service_name(*port, *protocol).unwrap_or("");
+                format!("{port}/{}/{protocol}//{service}///", port_status.state.name())
+            }).collect();
+            writeln!(self.writer, "{host}\tPorts: {}", ports.join(", "))?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, stats: &RunStats) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "# rmap done at {} -- {} IP addresses ({} hosts up) scanned in {:.2} seconds",
+            time_string(stats.end_time),
+            stats.hosts_total,
+            stats.hosts_up,
+            stats.elapsed.as_secs_f64(),
+        )?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::output::fixtures::{host_result, run_stats, scan_info};
+    use crate::scan::{PortState, PortStatus, Reason};
+    use crate::services::Protocol;
+
+    #[test]
+    fn test_grepable_lines() {
+        let scan = ScanInfo {
+            arguments: vec!["rmap".to_string(), "-oG".to_string(), "-".to_string()],
+            host_discovery: false,
+            ports: vec![(Protocol::Tcp, 22), (Protocol::Tcp, 8765)],
+            ..scan_info()
+        };
+        let port_states = vec![
+            ((Protocol::Tcp, 8765), PortStatus::new(PortState::Closed, Reason::ConnRefused)),
+            ((Protocol::Tcp, 22), PortStatus::new(PortState::Open, Reason::SynAck)),
+        ];
+        let result = HostResult { port_states: Some(port_states.into_iter().collect()), ..host_result() };
+        let stats = RunStats { elapsed: Duration::from_millis(1500), hosts_total: 2, ..run_stats() };
+
+        let mut output = vec![];
+        let mut grepable = GrepableWriter::new(&mut output, &scan).unwrap();
+        grepable.write_host(&result, Some(&HostName::Reverse("gw.example".to_string()))).unwrap();
+        grepable.write_host(&HostResult { port_states: None, ..result }, None).unwrap();
+        Box::new(grepable).finish(&stats).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert!(lines[0].starts_with("# rmap 0.1.0 scan initiated "));
+        assert!(lines[0].ends_with(" as: rmap -oG -"));
+        assert_eq!(lines[1], "Host: 192.0.2.1 (gw.example)\tStatus: Up");
+        assert_eq!(lines[2], "Host: 192.0.2.1 (gw.example)\tPorts: 22/open/tcp//ssh///, 8765/closed/tcp/////");
+        assert_eq!(lines[3], "Host: 192.0.2.1 ()\tStatus: Up");
+        assert!(lines[4].ends_with(" -- 2 IP addresses (1 hosts up) scanned in 1.50 seconds"));
+        assert_eq!(lines.len(), 5);
+    }
+}
@@ -0,0 +1,799 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::{FromIterator, Peekable};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use crate::shuffle::Permutation;
+
+/// Address of a single host to scan. Link-local IPv6 addresses keep the
+/// zone (interface index) they are reachable through, 0 means no zone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HostAddr {
+    pub ip: IpAddr,
+    pub scope_id: u32,
+}
+
+impl HostAddr {
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V4(ip), port),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.scope_id)),
+        }
+    }
+}
+
+impl From<IpAddr> for HostAddr {
+    fn from(ip: IpAddr) -> Self {
+        HostAddr { ip, scope_id: 0 }
+    }
+}
+
+impl PartialEq<Ipv4Addr> for HostAddr {
+    fn eq(&self, other: &Ipv4Addr) -> bool {
+        self.ip == *other
+    }
+}
+
+impl PartialEq<Ipv6Addr> for HostAddr {
+    fn eq(&self, other: &Ipv6Addr) -> bool {
+        self.ip == *other
+    }
+}
+
+impl fmt::Display for HostAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scope_id {
+            0 => write!(f, "{}", self.ip),
+            scope_id => write!(f, "{}%{}", self.ip, scope_id),
+        }
+    }
+}
+
+/// Name a host is reported with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostName {
+    /// The target was given by this name
+    Target(String),
+    /// Found by reverse lookup of the address
+    Reverse(String),
+}
+
+impl HostName {
+    pub fn name(&self) -> &str {
+        match self {
+            HostName::Target(name) | HostName::Reverse(name) => name,
+        }
+    }
+}
+
+/// Addresses described by a single host specification
+#[derive(Clone, Debug, PartialEq)]
+pub enum AddressPattern {
+    /// Consecutive addresses `first..=last` of the same address family
+    Range(IpAddr, IpAddr),
+    /// IPv4 addresses with a sorted list of values per octet, like `10.0-3.*.1`
+    Octets([Vec<u8>; 4]),
+}
+
+/// Consecutive addresses `first..=last` of one address family and zone.
+/// Blocks order by family and zone first, so sorted blocks only overlap
+/// when they belong to the same family and zone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Block {
+    ipv6: bool,
+    scope_id: u32,
+    first: u128,
+    last: u128,
+}
+
+impl Block {
+    fn host_count(&self) -> u128 {
+        // The full IPv6 space does not fit, saturate instead
+        (self.last - self.first).saturating_add(1)
+    }
+
+    fn same_space(&self, other: &Block) -> bool {
+        self.ipv6 == other.ipv6 && self.scope_id == other.scope_id
+    }
+
+    /// The other block overlaps or directly follows this one
+    fn touches(&self, other: &Block) -> bool {
+        self.same_space(other) && other.first <= self.last.saturating_add(1)
+    }
+
+    /// This block ends before the other one starts
+    fn precedes(&self, other: &Block) -> bool {
+        if self.same_space(other) {
+            self.last < other.first
+        } else {
+            (self.ipv6, self.scope_id) < (other.ipv6, other.scope_id)
+        }
+    }
+
+    fn host_addr(&self, value: u128) -> HostAddr {
+        let ip = if self.ipv6 {
+            IpAddr::V6(Ipv6Addr::from(value))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(value as u32))
+        };
+        HostAddr { ip, scope_id: self.scope_id }
+    }
+}
+
+fn ip_value(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(u32::from(ip)),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// Lazily generates the blocks of an address pattern in ascending order
+#[derive(Clone, Debug)]
+struct PatternBlocks {
+    pattern: AddressPattern,
+    scope_id: u32,
+    /// Octet that is split into runs of consecutive values, the octets
+    /// after it are complete and the ones before it are enumerated
+    split: usize,
+    runs: Vec<(u8, u8)>,
+    /// Position per octet, `None` when all blocks were returned
+    cursor: Option<[usize; 4]>,
+}
+
+impl PatternBlocks {
+    fn new(pattern: AddressPattern, scope_id: u32) -> Self {
+        let (split, runs) = match &pattern {
+            AddressPattern::Range(..) => (0, vec![]),
+            AddressPattern::Octets(octets) => {
+                let split = octets.iter().rposition(|values| values.len() != 256).unwrap_or(0);
+                (split, runs_from_values(&octets[split]))
+            }
+        };
+        let cursor = match &pattern {
+            AddressPattern::Octets(octets) if octets.iter().any(Vec::is_empty) => None,
+            _ => Some([0; 4]),
+        };
+        PatternBlocks { pattern, scope_id, split, runs, cursor }
+    }
+
+    /// Number of hosts of the whole pattern, independent of the cursor
+    fn host_count(&self) -> u128 {
+        match &self.pattern {
+            AddressPattern::Range(first, last) => (ip_value(*last) - ip_value(*first)).saturating_add(1),
+            AddressPattern::Octets(octets) => octets.iter().map(|values| values.len() as u128).product(),
+        }
+    }
+
+    /// Host at position `index` of the pattern in ascending order
+    fn nth_host(&self, mut index: u128) -> HostAddr {
+        match &self.pattern {
+            AddressPattern::Range(first, _) => Block {
+                ipv6: first.is_ipv6(),
+                scope_id: self.scope_id,
+                first: ip_value(*first),
+                last: ip_value(*first),
+            }
+            .host_addr(ip_value(*first) + index),
+            AddressPattern::Octets(octets) => {
+                let mut value = [0; 4];
+                for (octet, values) in octets.iter().enumerate().rev() {
+                    let len = values.len() as u128;
+                    value[octet] = values[(index % len) as usize];
+                    index /= len;
+                }
+                HostAddr { ip: Ipv4Addr::from(value).into(), scope_id: self.scope_id }
+            }
+        }
+    }
+
+    /// The pattern contains the address, in the same zone
+    fn contains(&self, host: &HostAddr) -> bool {
+        if host.scope_id != self.scope_id {
+            return false;
+        }
+        match (&self.pattern, host.ip) {
+            (AddressPattern::Range(first, last), ip) => {
+                first.is_ipv6() == ip.is_ipv6() && (ip_value(*first)..=ip_value(*last)).contains(&ip_value(ip))
+            }
+            (AddressPattern::Octets(octets), IpAddr::V4(ip)) => {
+                octets.iter().zip(ip.octets().iter()).all(|(values, octet)| values.binary_search(octet).is_ok())
+            }
+            (AddressPattern::Octets(_), IpAddr::V6(_)) => false,
+        }
+    }
+
+    fn is_range(&self) -> bool {
+        matches!(self.pattern, AddressPattern::Range(..))
+    }
+
+    fn is_ipv6(&self) -> bool {
+        matches!(self.pattern, AddressPattern::Range(first, _) if first.is_ipv6())
+    }
+
+    fn octet_len(&self, octets: &[Vec<u8>; 4], octet: usize) -> usize {
+        if octet == self.split {
+            self.runs.len()
+        } else {
+            octets[octet].len()
+        }
+    }
+}
+
+impl Iterator for PatternBlocks {
+    type Item = Block;
+    fn next(&mut self) -> Option<Block> {
+        let mut cursor = self.cursor?;
+        let octets = match &self.pattern {
+            AddressPattern::Range(first, last) => {
+                self.cursor = None;
+                return Some(Block {
+                    ipv6: first.is_ipv6(),
+                    scope_id: self.scope_id,
+                    first: ip_value(*first),
+                    last: ip_value(*last),
+                });
+            }
+            AddressPattern::Octets(octets) => octets,
+        };
+
+        let (mut first, mut last) = (0_u128, 0_u128);
+        for (octet, values) in octets.iter().enumerate() {
+            let (low, high) = if octet < self.split {
+                (values[cursor[octet]], values[cursor[octet]])
+            } else if octet == self.split {
+                self.runs[cursor[octet]]
+            } else {
+                (0, 255)
+            };
+            first = first << 8 | u128::from(low);
+            last = last << 8 | u128::from(high);
+        }
+
+        // Advance the cursor like an odometer, the split octet turns fastest
+        let mut octet = self.split;
+        self.cursor = loop {
+            cursor[octet] += 1;
+            if cursor[octet] < self.octet_len(octets, octet) {
+                break Some(cursor);
+            }
+            cursor[octet] = 0;
+            if octet == 0 {
+                break None;
+            }
+            octet -= 1;
+        };
+
+        Some(Block { ipv6: false, scope_id: self.scope_id, first, last })
+    }
+}
+
+/// Join sorted octet values to runs of consecutive values
+fn runs_from_values(values: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs: Vec<(u8, u8)> = vec![];
+    for &value in values {
+        match runs.last_mut() {
+            Some((_, last)) if u16::from(*last) + 1 == u16::from(value) => *last = value,
+            _ => runs.push((value, value)),
+        }
+    }
+    runs
+}
+
+/// Merges the sorted blocks of several patterns into sorted, disjoint blocks
+#[derive(Clone, Debug)]
+struct MergedBlocks {
+    sources: Vec<PatternBlocks>,
+    heads: BinaryHeap<Reverse<(Block, usize)>>,
+}
+
+impl MergedBlocks {
+    fn new(mut sources: Vec<PatternBlocks>) -> Self {
+        let heads = sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, source)| source.next().map(|block| Reverse((block, index))))
+            .collect();
+        MergedBlocks { sources, heads }
+    }
+
+    fn pop(&mut self) -> Option<Block> {
+        let Reverse((block, index)) = self.heads.pop()?;
+        if let Some(next) = self.sources[index].next() {
+            self.heads.push(Reverse((next, index)));
+        }
+        Some(block)
+    }
+}
+
+impl Iterator for MergedBlocks {
+    type Item = Block;
+    fn next(&mut self) -> Option<Block> {
+        let mut block = self.pop()?;
+        while let Some(Reverse((next, _))) = self.heads.peek() {
+            if !block.touches(next) {
+                break;
+            }
+            block.last = block.last.max(next.last);
+            self.pop();
+        }
+        Some(block)
+    }
+}
+
+/// Removes the excluded blocks from sorted, disjoint blocks. Both sides are
+/// walked in step, so the difference is never materialized.
+#[derive(Clone, Debug)]
+struct SubtractedBlocks {
+    blocks: MergedBlocks,
+    excluded: Peekable<MergedBlocks>,
+    /// Rest of a block that continues after an excluded block
+    pending: Option<Block>,
+}
+
+impl SubtractedBlocks {
+    fn new(patterns: Vec<PatternBlocks>, excluded: Vec<PatternBlocks>) -> Self {
+        SubtractedBlocks {
+            blocks: MergedBlocks::new(patterns),
+            excluded: MergedBlocks::new(excluded).peekable(),
+            pending: None,
+        }
+    }
+}
+
+impl Iterator for SubtractedBlocks {
+    type Item = Block;
+    fn next(&mut self) -> Option<Block> {
+        loop {
+            let mut block = self.pending.take().or_else(|| self.blocks.next())?;
+            while self.excluded.next_if(|excluded| excluded.precedes(&block)).is_some() {}
+
+            let excluded = match self.excluded.peek() {
+                Some(excluded) if excluded.same_space(&block) && excluded.first <= block.last => *excluded,
+                _ => return Some(block),
+            };
+            if excluded.last < block.last {
+                self.pending = Some(Block { first: excluded.last + 1, ..block });
+            }
+            if excluded.first > block.first {
+                block.last = excluded.first - 1;
+                return Some(block);
+            }
+        }
+    }
+}
+
+/// Iterator over the addresses of a host set in ascending order. Addresses
+/// are generated block by block, so large ranges are never held in memory.
+#[derive(Clone, Debug)]
+pub struct HostIpRange {
+    blocks: SubtractedBlocks,
+    current: Option<Block>,
+}
+
+impl HostIpRange {
+    fn new(mut blocks: SubtractedBlocks) -> Self {
+        let current = blocks.next();
+        HostIpRange { blocks, current }
+    }
+
+    /// Number of hosts not yet returned by the iterator
+    pub fn host_count(&self) -> u128 {
+        self.blocks
+            .clone()
+            .chain(self.current)
+            .fold(0, |count, block| count.saturating_add(block.host_count()))
+    }
+}
+
+impl Iterator for HostIpRange {
+    type Item = HostAddr;
+    fn next(&mut self) -> Option<HostAddr> {
+        let block = self.current.as_mut()?;
+        let host = block.host_addr(block.first);
+        if block.first < block.last {
+            block.first += 1;
+        } else {
+            self.current = self.blocks.next();
+        }
+        Some(host)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let count = self.host_count();
+        (usize::try_from(count).unwrap_or(usize::MAX), usize::try_from(count).ok())
+    }
+}
+
+/// Iterator over the addresses of a host set in a seeded random order. Each
+/// position of the permutation maps to one candidate address, candidates
+/// that are excluded or belong to an earlier pattern are skipped.
+#[derive(Clone, Debug)]
+pub struct RandomHostIter {
+    hosts: IndexedHosts,
+    candidates: u128,
+    permutation: Permutation,
+    next: u128,
+}
+
+/// Candidate addresses of a host set by position. Range patterns are few
+/// blocks each, so they are merged and subtracted up front. Octet patterns
+/// can span millions of blocks and are looked up in the pattern itself.
+#[derive(Clone, Debug)]
+struct IndexedHosts {
+    ranges: Vec<Block>,
+    octets: Vec<PatternBlocks>,
+    excluded_ranges: Vec<Block>,
+    excluded_octets: Vec<PatternBlocks>,
+    /// Position after each range block, followed by the position after each octet pattern
+    ends: Vec<u128>,
+}
+
+impl IndexedHosts {
+    fn new(patterns: &[PatternBlocks], excluded: &[PatternBlocks]) -> Self {
+        let (ranges, octets): (Vec<_>, Vec<_>) = patterns.iter().cloned().partition(PatternBlocks::is_range);
+        let (excluded_ranges, excluded_octets): (Vec<_>, Vec<_>) = excluded.iter().cloned().partition(PatternBlocks::is_range);
+        let ranges: Vec<Block> = SubtractedBlocks::new(ranges, excluded_ranges.clone()).collect();
+        let ends = ranges
+            .iter()
+            .map(Block::host_count)
+            .chain(octets.iter().map(PatternBlocks::host_count))
+            .scan(0_u128, |end, count| {
+                *end = end.saturating_add(count);
+                Some(*end)
+            })
+            .collect();
+        let excluded_ranges = MergedBlocks::new(excluded_ranges).collect();
+        IndexedHosts { ranges, octets, excluded_ranges, excluded_octets, ends }
+    }
+
+    fn candidates(&self) -> u128 {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    /// Candidate at position `index`, `None` if it is excluded or was
+    /// already the candidate of a range or an earlier octet pattern
+    fn nth_host(&self, index: u128) -> Option<HostAddr> {
+        let position = self.ends.partition_point(|end| *end <= index);
+        let offset = index - position.checked_sub(1).map_or(0, |previous| self.ends[previous]);
+        let host = match self.ranges.get(position) {
+            Some(block) => block.host_addr(block.first + offset),
+            None => {
+                let pattern = position - self.ranges.len();
+                let host = self.octets[pattern].nth_host(offset);
+                let earlier = blocks_contain(&self.ranges, &host)
+                    || self.octets[..pattern].iter().any(|octets| octets.contains(&host))
+                    || blocks_contain(&self.excluded_ranges, &host);
+                if earlier {
+                    return None;
+                }
+                host
+            }
+        };
+        Some(host).filter(|host| !self.excluded_octets.iter().any(|octets| octets.contains(host)))
+    }
+}
+
+/// Sorted, disjoint blocks contain the address
+fn blocks_contain(blocks: &[Block], host: &HostAddr) -> bool {
+    let value = ip_value(host.ip);
+    let point = Block { ipv6: host.ip.is_ipv6(), scope_id: host.scope_id, first: value, last: value };
+    let position = blocks.partition_point(|block| block.precedes(&point));
+    blocks.get(position).is_some_and(|block| block.same_space(&point) && block.first <= value)
+}
+
+impl Iterator for RandomHostIter {
+    type Item = HostAddr;
+    fn next(&mut self) -> Option<HostAddr> {
+        while self.next < self.candidates {
+            let index = self.permutation.get(self.next);
+            self.next += 1;
+            if let Some(host) = self.hosts.nth_host(index) {
+                return Some(host);
+            }
+        }
+        None
+    }
+}
+
+/// Hosts described by host specifications, with the host names they were given by.
+/// Each address is part of the set once, even if several specifications contain it.
+#[derive(Clone, Debug, Default)]
+pub struct HostSet {
+    patterns: Vec<PatternBlocks>,
+    excluded: Vec<PatternBlocks>,
+    names: HashMap<HostAddr, String>,
+}
+
+impl HostSet {
+    pub fn new(pattern: AddressPattern, scope_id: u32) -> Self {
+        HostSet { patterns: vec![PatternBlocks::new(pattern, scope_id)], ..HostSet::default() }
+    }
+
+    /// Walks all blocks of the set, unless it is a single pattern without
+    /// exclusions
+    pub fn host_count(&self) -> u128 {
+        match (self.patterns.as_slice(), self.excluded.as_slice()) {
+            ([pattern], []) => pattern.host_count(),
+            _ => self.iter().host_count(),
+        }
+    }
+
+    /// The set has exactly one host, without counting all of them
+    pub fn is_single_host(&self) -> bool {
+        let mut blocks = self.blocks();
+        matches!((blocks.next(), blocks.next()), (Some(block), None) if block.host_count() == 1)
+    }
+
+    /// Some target is an IPv4 address, exclusions are not taken into account
+    pub fn has_ipv4(&self) -> bool {
+        self.patterns.iter().any(|pattern| !pattern.is_ipv6())
+    }
+
+    /// Some target is an IPv6 address, exclusions are not taken into account
+    pub fn has_ipv6(&self) -> bool {
+        self.patterns.iter().any(PatternBlocks::is_ipv6)
+    }
+
+    /// Host name the address was resolved from, if it was given by name
+    pub fn name(&self, host: &HostAddr) -> Option<&str> {
+        self.names.get(host).map(String::as_str)
+    }
+
+    pub fn insert_name(&mut self, host: HostAddr, name: &str) {
+        self.names.entry(host).or_insert_with(|| name.to_string());
+    }
+
+    pub fn iter(&self) -> HostIpRange {
+        HostIpRange::new(self.blocks())
+    }
+
+    /// Iterate the hosts in a random order that only depends on the seed
+    pub fn iter_random(&self, seed: u64) -> RandomHostIter {
+        let hosts = IndexedHosts::new(&self.patterns, &self.excluded);
+        let candidates = hosts.candidates();
+        RandomHostIter { hosts, candidates, permutation: Permutation::new(candidates, seed), next: 0 }
+    }
+
+    fn blocks(&self) -> SubtractedBlocks {
+        SubtractedBlocks::new(self.patterns.clone(), self.excluded.clone())
+    }
+
+    /// Remove the hosts of another set, also when they are added later on.
+    /// Addresses only match within the same zone.
+    pub fn exclude(&mut self, excluded: HostSet) {
+        self.excluded.extend(excluded.patterns);
+    }
+
+    /// Add the hosts of another set.
+    /// The host name given first wins for addresses named more than once.
+    pub fn merge(&mut self, other: HostSet) {
+        self.patterns.extend(other.patterns);
+        self.excluded.extend(other.excluded);
+        for (host, name) in other.names {
+            self.names.entry(host).or_insert(name);
+        }
+    }
+}
+
+impl FromIterator<HostSet> for HostSet {
+    fn from_iter<I: IntoIterator<Item = HostSet>>(iter: I) -> Self {
+        let mut hosts = HostSet::default();
+        for other in iter {
+            hosts.merge(other);
+        }
+        hosts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn octets(octets: [&[u8]; 4]) -> AddressPattern {
+        AddressPattern::Octets([octets[0].to_vec(), octets[1].to_vec(), octets[2].to_vec(), octets[3].to_vec()])
+    }
+
+    fn all_values() -> Vec<u8> {
+        (0..=255).collect()
+    }
+
+    #[test]
+    fn test_octet_pattern_blocks_stay_lazy() {
+        let all = all_values();
+        let blocks: Vec<_> = PatternBlocks::new(octets([&[10], &all, &all, &all]), 0).collect();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].host_count(), 1 << 24);
+
+        let blocks = PatternBlocks::new(octets([&[10], &[0, 1, 2, 3], &all, &[1]]), 0);
+        assert_eq!(blocks.count(), 4 * 256);
+
+        let blocks: Vec<_> = PatternBlocks::new(octets([&[192], &[168], &[1], &[10, 11, 12, 20]]), 0)
+            .map(|block| (block.first as u8, block.last as u8))
+            .collect();
+        assert_eq!(blocks, [(10, 12), (20, 20)]);
+    }
+
+    #[test]
+    fn test_octet_pattern_addresses_ascend() {
+        let hosts = HostSet::new(octets([&[10], &[0, 1], &[5], &[1, 2]]), 0);
+        assert!(hosts.iter().eq([
+            Ipv4Addr::new(10, 0, 5, 1),
+            Ipv4Addr::new(10, 0, 5, 2),
+            Ipv4Addr::new(10, 1, 5, 1),
+            Ipv4Addr::new(10, 1, 5, 2),
+        ].iter().map(|ip| HostAddr::from(IpAddr::from(*ip)))));
+    }
+
+    #[test]
+    fn test_empty_octet_pattern() {
+        let hosts = HostSet::new(octets([&[10], &[], &[5], &[1, 2]]), 0);
+        assert_eq!(hosts.host_count(), 0);
+        assert!(hosts.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_merged_blocks_are_disjoint() {
+        let range = |first: [u8; 4], last: [u8; 4]| {
+            HostSet::new(AddressPattern::Range(Ipv4Addr::from(first).into(), Ipv4Addr::from(last).into()), 0)
+        };
+        let hosts: HostSet = vec![
+            range([10, 0, 0, 0], [10, 0, 0, 9]),
+            range([10, 0, 0, 5], [10, 0, 0, 20]),
+            range([10, 0, 0, 21], [10, 0, 0, 21]),
+            range([10, 0, 1, 0], [10, 0, 1, 0]),
+            HostSet::new(octets([&[10], &[0], &[0, 1], &[0, 1, 2, 3]]), 0),
+        ].into_iter().collect();
+
+        let hosts_iter = hosts.iter();
+        let blocks: Vec<_> = hosts_iter.current.into_iter().chain(hosts_iter.blocks).collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(hosts.host_count(), 22 + 4);
+        assert_eq!(hosts.iter().count(), 22 + 4);
+    }
+
+    #[test]
+    fn test_excluded_blocks_are_subtracted() {
+        let range = |first: u32, last: u32| {
+            HostSet::new(AddressPattern::Range(Ipv4Addr::from(first).into(), Ipv4Addr::from(last).into()), 0)
+        };
+        let mut hosts: HostSet = vec![range(0, 99), range(200, 299), range(400, 400)].into_iter().collect();
+        hosts.merge(HostSet::new(AddressPattern::Range(Ipv6Addr::from(50).into(), Ipv6Addr::from(60).into()), 0));
+        hosts.exclude(vec![range(0, 9), range(20, 29), range(90, 209), range(250, 250), range(400, 500)].into_iter().collect());
+
+        let hosts_iter = hosts.iter();
+        let blocks: Vec<_> = hosts_iter.current.into_iter().chain(hosts_iter.blocks)
+            .map(|block| (block.ipv6, block.first, block.last))
+            .collect();
+        assert_eq!(blocks, [
+            (false, 10, 19),
+            (false, 30, 89),
+            (false, 210, 249),
+            (false, 251, 299),
+            (true, 50, 60),
+        ]);
+        assert_eq!(hosts.host_count(), 10 + 60 + 40 + 49 + 11);
+    }
+
+    #[test]
+    fn test_exclude_everything() {
+        let all = all_values();
+        let mut hosts = HostSet::new(octets([&[10], &[0, 1], &all, &[1]]), 0);
+        hosts.exclude(HostSet::new(octets([&[10], &all, &all, &all]), 0));
+        assert!(hosts.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_random_order_matches_sorted_order() {
+        let range = |first: u32, last: u32| {
+            HostSet::new(AddressPattern::Range(Ipv4Addr::from(first).into(), Ipv4Addr::from(last).into()), 0)
+        };
+        let mut hosts: HostSet = vec![
+            range(0, 99),
+            range(50, 149),
+            HostSet::new(octets([&[0], &[0], &[0, 1], &[1, 3, 200]]), 0),
+            HostSet::new(AddressPattern::Range(Ipv6Addr::from(1).into(), Ipv6Addr::from(9).into()), 0),
+        ].into_iter().collect();
+        hosts.exclude(range(10, 19));
+
+        let mut random: Vec<_> = hosts.iter_random(9).collect();
+        assert_ne!(random, hosts.iter().collect::<Vec<_>>());
+        assert_eq!(random, hosts.iter_random(9).collect::<Vec<_>>());
+        random.sort();
+        assert_eq!(random, hosts.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_random_order_of_single_pattern() {
+        let all = all_values();
+        let mut hosts = HostSet::new(octets([&[10], &[0, 1], &all, &[1]]), 0);
+        let mut random: Vec<_> = hosts.iter_random(3).collect();
+        random.sort();
+        assert_eq!(random, hosts.iter().collect::<Vec<_>>());
+
+        hosts.exclude(HostSet::new(octets([&[10], &[0], &all, &all]), 0));
+        let random: Vec<_> = hosts.iter_random(3).collect();
+        assert_eq!(random.len(), 256);
+        assert!(random.iter().all(|host| matches!(host.ip, IpAddr::V4(ip) if ip.octets()[1] == 1)));
+    }
+
+    #[test]
+    fn test_random_order_of_overlapping_octet_patterns() {
+        let all = all_values();
+        let range = |first: [u8; 4], last: [u8; 4]| {
+            HostSet::new(AddressPattern::Range(Ipv4Addr::from(first).into(), Ipv4Addr::from(last).into()), 0)
+        };
+        let mut hosts: HostSet = vec![
+            HostSet::new(octets([&[10], &[0, 1], &all, &[1, 2]]), 0),
+            HostSet::new(octets([&[10], &[1, 2], &all, &[2, 3]]), 0),
+            range([10, 0, 0, 0], [10, 0, 3, 255]),
+        ].into_iter().collect();
+        hosts.exclude(vec![
+            range([10, 0, 1, 0], [10, 0, 1, 255]),
+            HostSet::new(octets([&[10], &all, &all, &[3]]), 0),
+        ].into_iter().collect());
+
+        let mut random: Vec<_> = hosts.iter_random(5).collect();
+        random.sort();
+        assert_eq!(random, hosts.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_random_order_with_exclusion_stays_lazy() {
+        let all = all_values();
+        let mut hosts = HostSet::new(octets([&all, &all, &all, &[1]]), 0);
+        let excluded = Ipv4Addr::new(1, 1, 1, 1);
+        hosts.exclude(HostSet::new(AddressPattern::Range(excluded.into(), excluded.into()), 0));
+        let random = hosts.iter_random(7);
+        assert!(random.hosts.ranges.is_empty());
+        assert_eq!(random.hosts.excluded_ranges.len(), 1);
+        assert_eq!(random.candidates, 1 << 24);
+        let sample: Vec<_> = random.take(1000).collect();
+        assert_eq!(sample.len(), 1000);
+        assert!(sample.iter().all(|host| matches!(host.ip, IpAddr::V4(ip) if ip.octets()[3] == 1 && ip != excluded)));
+    }
+
+    #[test]
+    fn test_nth_host_of_octet_pattern() {
+        let pattern = PatternBlocks::new(octets([&[10], &[0, 1, 2, 3], &[7], &[1, 2]]), 0);
+        assert_eq!(pattern.host_count(), 8);
+        assert_eq!(pattern.nth_host(0), Ipv4Addr::new(10, 0, 7, 1));
+        assert_eq!(pattern.nth_host(5), Ipv4Addr::new(10, 2, 7, 2));
+        assert_eq!(pattern.nth_host(7), Ipv4Addr::new(10, 3, 7, 2));
+    }
+
+    #[test]
+    fn test_is_single_host() {
+        let range = |first: u32, last: u32| {
+            HostSet::new(AddressPattern::Range(Ipv4Addr::from(first).into(), Ipv4Addr::from(last).into()), 0)
+        };
+        assert!(range(5, 5).is_single_host());
+        assert!(!range(5, 6).is_single_host());
+        assert!(!HostSet::default().is_single_host());
+        let mut hosts: HostSet = vec![range(5, 5), range(5, 5)].into_iter().collect();
+        assert!(hosts.is_single_host());
+        hosts.merge(range(7, 7));
+        assert!(!hosts.is_single_host());
+        hosts.exclude(range(6, 9));
+        assert!(hosts.is_single_host());
+    }
+
+    #[test]
+    fn test_address_families() {
+        let ipv4 = HostSet::new(octets([&[10], &[0], &[0], &[1, 2]]), 0);
+        assert!(ipv4.has_ipv4() && !ipv4.has_ipv6());
+        let mut hosts = HostSet::new(AddressPattern::Range(Ipv6Addr::from(1).into(), Ipv6Addr::from(9).into()), 0);
+        assert!(!hosts.has_ipv4() && hosts.has_ipv6());
+        hosts.merge(ipv4);
+        assert!(hosts.has_ipv4() && hosts.has_ipv6());
+        assert!(!HostSet::default().has_ipv4() && !HostSet::default().has_ipv6());
+    }
+
+    #[test]
+    fn test_whole_address_space() {
+        let hosts = HostSet::new(AddressPattern::Range(Ipv6Addr::UNSPECIFIED.into(), Ipv6Addr::from(u128::MAX).into()), 0);
+        assert_eq!(hosts.host_count(), u128::MAX);
+        assert_eq!(hosts.iter().nth(1).unwrap(), Ipv6Addr::from(1));
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::hosts::HostAddr;
+use crate::raw::{internet_checksum, ipv4_payload, is_transient, raw_socket, recv_from, send_to};
+use crate::shuffle::SplitMix64;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIMESTAMP_REQUEST: u8 = 13;
+const ICMP_TIMESTAMP_REPLY: u8 = 14;
+const ICMP_ADDRESS_MASK_REQUEST: u8 = 17;
+const ICMP_ADDRESS_MASK_REPLY: u8 = 18;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// ICMP requests answered by hosts that are up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpProbe {
+    Echo,
+    /// Only exists for IPv4 and needs a raw socket
+    Timestamp,
+    /// Only exists for IPv4 and needs a raw socket
+    AddressMask,
+}
+
+/// Requests waiting for a reply, by remote address and sequence number
+type Pending = Arc<Mutex<HashMap<(IpAddr, u16), oneshot::Sender<Instant>>>>;
+
+/// Raw socket, or an unprivileged datagram socket that only sends echo requests
+struct IcmpSocket {
+    socket: Arc<AsyncFd<Socket>>,
+    raw: bool,
+    /// Datagram sockets get their identifier from the kernel
+    identifier: u16,
+}
+
+impl IcmpSocket {
+    /// Datagram sockets are allowed for the groups in `net.ipv4.ping_group_range`
+    fn open(ipv6: bool, identifier: u16) -> io::Result<Self> {
+        let (domain, protocol) = if ipv6 { (Domain::IPV6, Protocol::ICMPV6) } else { (Domain::IPV4, Protocol::ICMPV4) };
+        if let Ok(socket) = raw_socket(domain, protocol) {
+            return Ok(IcmpSocket { socket: Arc::new(socket), raw: true, identifier });
+        }
+
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        let unspecified = if ipv6 {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+        } else {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+        };
+        socket.bind(&unspecified.into())?;
+        // The local port of a datagram socket is the identifier of its requests
+        let identifier = socket.local_addr()?.as_socket().map_or(0, |local| local.port());
+        // SAFETY: the socket owns its file descriptor and is only closed by dropping the AsyncFd
+        let socket = unsafe { AsyncFd::register(socket)? };
+        Ok(IcmpSocket { socket: Arc::new(socket), raw: false, identifier })
+    }
+
+    fn supports(&self, probe: IcmpProbe) -> bool {
+        self.raw || probe == IcmpProbe::Echo
+    }
+}
+
+/// Sends ICMP requests, a listener task per address family matches the
+/// replies by identifier and sequence number.
+pub struct IcmpPinger {
+    ipv4: Option<IcmpSocket>,
+    ipv6: Option<IcmpSocket>,
+    sequence: AtomicU16,
+    pending: Pending,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl IcmpPinger {
+    /// Open raw sockets, or datagram sockets without CAP_NET_RAW. Fails if
+    /// neither address family has a socket.
+    /// Must be called from within the tokio runtime.
+    pub fn new(seed: u64) -> io::Result<Self> {
+        let identifier = SplitMix64::new(seed).next_u64() as u16;
+        let ipv4 = IcmpSocket::open(false, identifier);
+        let ipv6 = IcmpSocket::open(true, identifier).ok();
+        let ipv4 = match (ipv4, &ipv6) {
+            (Ok(ipv4), _) => Some(ipv4),
+            (Err(_), Some(_)) => None,
+            (Err(err), None) => return Err(err),
+        };
+
+        let pending = Pending::default();
+        let listeners = [(&ipv4, false), (&ipv6, true)]
+            .iter()
+            .filter_map(|(socket, ipv6)| {
+                let socket = socket.as_ref()?;
+                let has_ip_header = socket.raw && !ipv6;
+                Some(tokio::spawn(listen(socket.socket.clone(), *ipv6, has_ip_header, socket.identifier, pending.clone())))
+            })
+            .collect();
+        Ok(IcmpPinger { ipv4, ipv6, sequence: AtomicU16::new(0), pending, listeners })
+    }
+
+    /// Whether the probe can be sent to the host at all
+    pub fn supports(&self, host: HostAddr, probe: IcmpProbe) -> bool {
+        match host.ip {
+            IpAddr::V4(_) => self.ipv4.as_ref().is_some_and(|socket| socket.supports(probe)),
+            IpAddr::V6(_) => self.ipv6.is_some() && probe == IcmpProbe::Echo,
+        }
+    }
+
+    /// Round trip time of the request, `None` without a reply
+    pub async fn probe(&self, host: HostAddr, probe: IcmpProbe, timeout: Duration) -> Option<Duration> {
+        if !self.supports(host, probe) {
+            return None;
+        }
+        let socket = if host.ip.is_ipv6() { self.ipv6.as_ref()? } else { self.ipv4.as_ref()? };
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let (sender, reply) = oneshot::channel();
+        self.pending.lock().unwrap().insert((host.ip, sequence), sender);
+
+        let packet = icmp_request(probe, host.ip.is_ipv6(), socket.identifier, sequence);
+        let sent = Instant::now();
+        let rtt = match send_to(&socket.socket, &packet, &SockAddr::from(host.socket_addr(0))).await {
+            Ok(()) => match tokio::time::timeout(timeout, reply).await {
+                Ok(Ok(received)) => Some(received - sent),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+        self.pending.lock().unwrap().remove(&(host.ip, sequence));
+        rtt
+    }
+}
+
+impl Drop for IcmpPinger {
+    fn drop(&mut self) {
+        self.listeners.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Echo, timestamp and address mask requests share the layout of their
+/// first eight bytes. The kernel computes the checksum of ICMPv6 messages
+/// and of messages sent from datagram sockets itself.
+fn icmp_request(probe: IcmpProbe, ipv6: bool, identifier: u16, sequence: u16) -> Vec<u8> {
+    let (request_type, payload): (u8, &[u8]) = match (probe, ipv6) {
+        (IcmpProbe::Echo, false) => (ICMP_ECHO_REQUEST, b"rmap-png"),
+        (IcmpProbe::Echo, true) => (ICMPV6_ECHO_REQUEST, b"rmap-png"),
+        // Originate, receive and transmit timestamp
+        (IcmpProbe::Timestamp, _) => (ICMP_TIMESTAMP_REQUEST, &[0; 12]),
+        (IcmpProbe::AddressMask, _) => (ICMP_ADDRESS_MASK_REQUEST, &[0; 4]),
+    };
+    let mut packet = vec![request_type, 0, 0, 0];
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(payload);
+    if !ipv6 {
+        let checksum = internet_checksum(packet.iter());
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Remote address and sequence number of a reply with our identifier
+fn parse_reply(packet: &[u8], from: IpAddr, ipv6: bool, has_ip_header: bool, identifier: u16) -> Option<(IpAddr, u16)> {
+    let (remote, message) = if has_ip_header { ipv4_payload(packet)? } else { (from, packet) };
+    let is_reply = if ipv6 {
+        message.first() == Some(&ICMPV6_ECHO_REPLY)
+    } else {
+        matches!(message.first(), Some(&ICMP_ECHO_REPLY) | Some(&ICMP_TIMESTAMP_REPLY) | Some(&ICMP_ADDRESS_MASK_REPLY))
+    };
+    if message.len() < 8 || !is_reply || message[4..6] != identifier.to_be_bytes() {
+        return None;
+    }
+    Some((remote, u16::from_be_bytes([message[6], message[7]])))
+}
+
+async fn listen(socket: Arc<AsyncFd<Socket>>, ipv6: bool, has_ip_header: bool, identifier: u16, pending: Pending) {
+    let mut buffer = vec![MaybeUninit::<u8>::uninit(); 65535];
+    loop {
+        let (packet, from) = match recv_from(&socket, &mut buffer).await {
+            Ok(received) => received,
+            Err(err) if is_transient(&err) => continue,
+            // Dropping the senders ends the waiting requests without a reply
+            Err(_) => return pending.lock().unwrap().clear(),
+        };
+        let received = Instant::now();
+        let from = match from.as_socket() {
+            Some(from) => from.ip(),
+            None => continue,
+        };
+        if let Some(reply) = parse_reply(packet, from, ipv6, has_ip_header, identifier) {
+            if let Some(sender) = pending.lock().unwrap().remove(&reply) {
+                let _ = sender.send(received);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icmp_request_checksum_verifies() {
+        for probe in [IcmpProbe::Echo, IcmpProbe::Timestamp, IcmpProbe::AddressMask] {
+            let packet = icmp_request(probe, false, 0x1337, 5);
+            assert_eq!(internet_checksum(packet.iter()), 0);
+        }
+        assert_eq!(icmp_request(IcmpProbe::Timestamp, false, 1, 1).len(), 20);
+        assert_eq!(icmp_request(IcmpProbe::AddressMask, false, 1, 1).len(), 12);
+        assert_eq!(icmp_request(IcmpProbe::Echo, true, 0x1337, 5)[0..4], [ICMPV6_ECHO_REQUEST, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let mut packet = vec![0x45, 0, 0, 36, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        packet.extend_from_slice(&icmp_request(IcmpProbe::Echo, false, 0x1337, 5));
+        let from = IpAddr::from(Ipv4Addr::UNSPECIFIED);
+        assert_eq!(parse_reply(&packet, from, false, true, 0x1337), None);
+
+        packet[20] = ICMP_ECHO_REPLY;
+        assert_eq!(parse_reply(&packet, from, false, true, 0x1337), Some((Ipv4Addr::new(10, 0, 0, 1).into(), 5)));
+        assert_eq!(parse_reply(&packet, from, false, true, 0x1338), None);
+        packet[20] = ICMP_TIMESTAMP_REPLY;
+        assert_eq!(parse_reply(&packet, from, false, true, 0x1337), Some((Ipv4Addr::new(10, 0, 0, 1).into(), 5)));
+
+        // Datagram sockets receive the message without IP header
+        assert_eq!(parse_reply(&packet[20..], from, false, false, 0x1337), Some((from, 5)));
+
+        let mut packet = icmp_request(IcmpProbe::Echo, true, 0x1337, 6);
+        packet[0] = ICMPV6_ECHO_REPLY;
+        assert_eq!(parse_reply(&packet, from, true, false, 0x1337), Some((from, 6)));
+    }
+}
@@ -0,0 +1,325 @@
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+
+use crate::hosts::HostName;
+use crate::output::{port_ranges, Output, RunStats, ScanInfo};
+use crate::scan::HostResult;
+use crate::services::{service_name, Protocol};
+use crate::timing::TimingPolicy;
+
+/// Version of the JSON schema described in the README. Raised when fields
+/// are removed or change their meaning, new fields keep the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const SCANNER: &str = "rmap";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize)]
+struct Scan<'a> {
+    arguments: &'a [String],
+    #[serde(serialize_with = "unix_time")]
+    start_time: SystemTime,
+    scan_types: &'a [&'static str],
+    host_discovery: bool,
+    #[serde(serialize_with = "serialize_port_ranges")]
+    ports: &'a [(Protocol, u16)],
+    seed: u64,
+    #[serde(serialize_with = "serialize_timing")]
+    timing: TimingPolicy,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    #[serde(serialize_with = "unix_time")]
+    end_time: SystemTime,
+    #[serde(serialize_with = "seconds")]
+    elapsed_seconds: Duration,
+    hosts_total: u128,
+    hosts_up: u64,
+}
+
+#[derive(Serialize)]
+struct Host<'a> {
+    address: String,
+    address_type: &'static str,
+    hostname: Option<Hostname<'a>>,
+    mac: Option<String>,
+    vendor: Option<&'static str>,
+    #[serde(serialize_with = "unix_time")]
+    start_time: SystemTime,
+    #[serde(serialize_with = "unix_time")]
+    end_time: SystemTime,
+    times: Times,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<Port>>,
+}
+
+#[derive(Serialize)]
+struct Hostname<'a> {
+    name: &'a str,
+    /// `user` for names given as target, `ptr` for reverse lookups, like nmap
+    #[serde(rename = "type")]
+    name_type: &'static str,
+}
+
+/// Round trip times and the final timeout in milliseconds
+#[derive(Serialize)]
+struct Times {
+    rtt: Option<f64>,
+    srtt: Option<f64>,
+    rttvar: Option<f64>,
+    timeout: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Port {
+    protocol: String,
+    port: u16,
+    service: Option<&'static str>,
+    state: &'static str,
+    reason: String,
+}
+
+/// One line of NDJSON output. The scan record has the fields of the JSON
+/// document before its hosts, so both share one schema.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record<'a> {
+    Scan {
+        schema_version: u32,
+        scanner: &'static str,
+        version: &'static str,
+        scan: Scan<'a>,
+    },
+    Host(Host<'a>),
+    Stats(Stats),
+}
+
+impl<'a> From<&'a ScanInfo> for Scan<'a> {
+    fn from(scan: &'a ScanInfo) -> Self {
+        Scan {
+            arguments: &scan.arguments,
+            start_time: scan.start_time,
+            scan_types: &scan.scan_types,
+            host_discovery: scan.host_discovery,
+            ports: &scan.ports,
+            seed: scan.seed,
+            timing: scan.timing,
+        }
+    }
+}
+
+impl From<&RunStats> for Stats {
+    fn from(stats: &RunStats) -> Self {
+        Stats { end_time: stats.end_time, elapsed_seconds: stats.elapsed, hosts_total: stats.hosts_total, hosts_up: stats.hosts_up }
+    }
+}
+
+impl<'a> Host<'a> {
+    fn new(result: &HostResult, name: Option<&'a HostName>) -> Self {
+        let address_type = match result.host.ip {
+            IpAddr::V4(_) => "ipv4",
+            IpAddr::V6(_) => "ipv6",
+        };
+        let hostname = name.map(|name| Hostname {
+            name: name.name(),
+            name_type: match name {
+                HostName::Target(_) => "user",
+                HostName::Reverse(_) => "ptr",
+            },
+        });
+        let rtt_stats = result.rtt_stats;
+        let ports = result.port_states.as_ref().map(|port_states| {
+            port_states.iter().map(|((protocol, port), port_status)| Port {
+                protocol: protocol.to_string(),
+                port: *port,
+                service: service_name(*port, *protocol),
+                state: port_status.state.name(),
+                reason: port_status.reason.to_string(),
+            }).collect()
+        });
+        Host {
+            address: result.host.to_string(),
+            address_type,
+            hostname,
+            mac: result.up.mac.map(|mac| mac.to_string()),
+            vendor: result.up.mac.and_then(|mac| mac.vendor()),
+            start_time: result.started,
+            end_time: result.finished,
+            times: Times {
+                rtt: result.up.rtt.map(milliseconds),
+                srtt: rtt_stats.map(|stats| milliseconds(stats.srtt)),
+                rttvar: rtt_stats.map(|stats| milliseconds(stats.rttvar)),
+                timeout: rtt_stats.map(|stats| milliseconds(stats.timeout)),
+            },
+            ports,
+        }
+    }
+}
+
+/// Writes the JSON document while the hosts finish, without keeping them in
+/// memory: `{"schema_version": .., "scanner": .., "version": .., "scan": ..,
+/// "hosts": [..], "stats": ..}`
+pub struct JsonWriter<W: Write> {
+    writer: W,
+    hosts: u64,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(mut writer: W, scan: &ScanInfo) -> io::Result<Self> {
+        write!(writer, "{{\"schema_version\":{SCHEMA_VERSION},\"scanner\":\"{SCANNER}\",\"version\":\"{VERSION}\",\"scan\":")?;
+        serde_json::to_writer(&mut writer, &Scan::from(scan))?;
+        writer.write_all(b",\"hosts\":[")?;
+        Ok(JsonWriter { writer, hosts: 0 })
+    }
+}
+
+impl<W: Write> Output for JsonWriter<W> {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()> {
+        self.writer.write_all(if self.hosts == 0 { b"\n" } else { b",\n" })?;
+        serde_json::to_writer(&mut self.writer, &Host::new(result, name))?;
+        self.hosts += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, stats: &RunStats) -> io::Result<()> {
+        self.writer.write_all(b"\n],\"stats\":")?;
+        serde_json::to_writer(&mut self.writer, &Stats::from(stats))?;
+        self.writer.write_all(b"}\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Writes one JSON object per line: the scan, then each host as it
+/// finishes, then the stats. Objects are told apart by their `type`.
+pub struct NdjsonWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(writer: W, scan: &ScanInfo) -> io::Result<Self> {
+        let mut ndjson = NdjsonWriter { writer };
+        let scan = Scan::from(scan);
+        ndjson.write_record(&Record::Scan { schema_version: SCHEMA_VERSION, scanner: SCANNER, version: VERSION, scan })?;
+        Ok(ndjson)
+    }
+
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Output for NdjsonWriter<W> {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()> {
+        self.write_record(&Record::Host(Host::new(result, name)))
+    }
+
+    fn finish(mut self: Box<Self>, stats: &RunStats) -> io::Result<()> {
+        self.write_record(&Record::Stats(Stats::from(stats)))
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Seconds since the Unix epoch, with fractions
+fn unix_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64())
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+fn serialize_port_ranges<S: Serializer>(ports: &[(Protocol, u16)], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(port_ranges(ports).into_iter().map(|(protocol, ranges)| (protocol.to_string(), ranges)))
+}
+
+/// Timeouts in milliseconds and rates in probes per second
+fn serialize_timing<S: Serializer>(timing: &TimingPolicy, serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Timing {
+        initial_rtt_timeout: f64,
+        min_rtt_timeout: f64,
+        max_rtt_timeout: f64,
+        max_retries: u8,
+        max_parallelism: usize,
+        min_parallelism: usize,
+        max_hostgroup: usize,
+        max_rate: Option<f64>,
+        min_rate: Option<f64>,
+        max_host_rate: Option<f64>,
+    }
+    Timing {
+        initial_rtt_timeout: milliseconds(timing.initial_rtt_timeout),
+        min_rtt_timeout: milliseconds(timing.min_rtt_timeout),
+        max_rtt_timeout: milliseconds(timing.max_rtt_timeout),
+        max_retries: timing.max_retries,
+        max_parallelism: timing.max_parallelism,
+        min_parallelism: timing.min_parallelism,
+        max_hostgroup: timing.max_hostgroup,
+        max_rate: timing.max_rate,
+        min_rate: timing.min_rate,
+        max_host_rate: timing.max_host_rate,
+    }.serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::fixtures::{host_result, run_stats, scan_info};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_json_document() {
+        let mut output = vec![];
+        let mut json = JsonWriter::new(&mut output, &scan_info()).unwrap();
+        let name = HostName::Target("gateway.example".to_string());
+        json.write_host(&host_result(), Some(&name)).unwrap();
+        json.write_host(&host_result(), None).unwrap();
+        Box::new(json).finish(&run_stats()).unwrap();
+
+        let document: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(document["schema_version"], SCHEMA_VERSION);
+        assert_eq!(document["scan"]["ports"], json!({"tcp": "22,80-81"}));
+        assert_eq!(document["scan"]["timing"]["initial_rtt_timeout"], 1000.0);
+        assert_eq!(document["hosts"][0]["hostname"], json!({"name": "gateway.example", "type": "user"}));
+        assert_eq!(document["hosts"][0]["start_time"], 1000.5);
+        assert_eq!(document["hosts"][0]["times"]["rtt"], 2.0);
+        assert_eq!(document["hosts"][0]["ports"], json!([
+            {"protocol": "tcp", "port": 22, "service": "ssh", "state": "open", "reason": "syn-ack"}
+        ]));
+        assert_eq!(document["hosts"][1]["hostname"], Value::Null);
+        assert_eq!(document["stats"]["elapsed_seconds"], 2.0);
+    }
+
+    #[test]
+    fn test_ndjson_lines() {
+        let mut output = vec![];
+        let mut ndjson = NdjsonWriter::new(&mut output, &scan_info()).unwrap();
+        let ping_only = HostResult { port_states: None, ..host_result() };
+        ndjson.write_host(&ping_only, None).unwrap();
+        Box::new(ndjson).finish(&run_stats()).unwrap();
+
+        let lines: Vec<Value> = output.split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["type"], "scan");
+        assert_eq!(lines[0]["schema_version"], SCHEMA_VERSION);
+        assert_eq!(lines[0]["scan"]["seed"], 7);
+        assert_eq!(lines[0]["scan"]["ports"], json!({"tcp": "22,80-81"}));
+        assert_eq!(lines[1]["type"], "host");
+        assert_eq!(lines[1]["address"], "192.0.2.1");
+        assert!(lines[1].get("ports").is_none());
+        assert_eq!(lines[2]["type"], "stats");
+        assert_eq!(lines[2]["hosts_up"], 1);
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Bundled table of MAC address prefixes and their vendors
+static MAC_PREFIXES_TABLE: &str = include_str!("../data/rmap-mac-prefixes");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Vendor of the network interface, `None` for unknown and locally administered addresses
+    pub fn vendor(&self) -> Option<&'static str> {
+        if self.0[0] & 0x02 != 0 && !self.is_qemu() {
+            return None;
+        }
+        mac_prefixes().get(&[self.0[0], self.0[1], self.0[2]]).copied()
+    }
+
+    /// QEMU's default prefix is locally administered, but well known
+    fn is_qemu(&self) -> bool {
+        self.0[..3] == [0x52, 0x54, 0x00]
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}")
+    }
+}
+
+fn mac_prefixes() -> &'static HashMap<[u8; 3], &'static str> {
+    static MAC_PREFIXES: OnceLock<HashMap<[u8; 3], &'static str>> = OnceLock::new();
+    MAC_PREFIXES.get_or_init(|| parse_mac_prefixes(MAC_PREFIXES_TABLE))
+}
+
+/// Lines have the format `OUI vendor` with the OUI as six hex digits, `#` starts
+/// a comment. The table is bundled with rmap, so malformed lines are a bug.
+fn parse_mac_prefixes(table: &'static str) -> HashMap<[u8; 3], &'static str> {
+    table
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .map(|line| {
+            let (prefix, vendor) = line.split_once(' ').expect("MAC prefix without vendor");
+            let prefix = u32::from_str_radix(prefix, 16).expect("Bad MAC prefix");
+            let [_, a, b, c] = prefix.to_be_bytes();
+            ([a, b, c], vendor.trim())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_mac_prefixes_parse() {
+        assert!(mac_prefixes().len() > 50);
+    }
+
+    #[test]
+    fn test_mac_vendor() {
+        assert_eq!(MacAddr([0x00, 0x50, 0x56, 0x12, 0x34, 0x56]).vendor(), Some("VMware"));
+        assert_eq!(MacAddr([0xb8, 0x27, 0xeb, 0, 0, 1]).vendor(), Some("Raspberry Pi Foundation"));
+        assert_eq!(MacAddr([0x52, 0x54, 0x00, 0, 0, 1]).vendor(), Some("QEMU virtual NIC"));
+        assert_eq!(MacAddr([0x02, 0x50, 0x56, 0, 0, 1]).vendor(), None);
+        assert_eq!(MacAddr([0x00, 0x00, 0x01, 0, 0, 1]).vendor(), None);
+    }
+
+    #[test]
+    fn test_display_mac() {
+        assert_eq!(MacAddr([0x02, 0xfc, 0, 0, 0, 0x1a]).to_string(), "02:FC:00:00:00:1A");
+    }
+}
@@ -1,105 +1,756 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::time::Duration;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
 use dns_lookup::lookup_addr;
-use futures::stream;
-use futures::StreamExt;
+use futures::{future, stream, Future, Stream, StreamExt};
+use tokio::sync::mpsc;
 
-use crate::args::{expand_hosts, expand_port_list};
+use crate::args::{expand_hosts, expand_port_list, parse_target_list, HostSpecOptions, NetworkParseError};
+use crate::arp::ArpScanner;
+use crate::discovery::{Discovery, HostUp, PingType};
+use crate::hosts::{HostAddr, HostName, HostSet};
+use crate::icmp::IcmpPinger;
+use crate::output::{Destination, Format, OutputError, Outputs, RunStats, ScanInfo};
+use crate::parallelism::{file_descriptor_capacity, raise_file_descriptor_limit};
+use crate::scan::{get_port_states, HostResult, PortState, ProbeLimits, TcpScan};
+use crate::services::{known_port_count, top_ports, Protocol};
+use crate::shuffle::{shuffle, SplitMix64};
+use crate::syn::SynScanner;
+use crate::timing::{TimingPolicy, TimingTemplate};
 
 mod args;
+mod arp;
+mod csv;
+mod discovery;
+mod grepable;
+mod hosts;
+mod icmp;
+mod json;
+mod mac;
+mod output;
+mod parallelism;
+mod rate;
+mod raw;
+mod rtt;
+mod scan;
+mod services;
+mod shuffle;
+mod syn;
+mod text;
+mod timing;
+mod udp;
+mod xml;
+
+/// Exit codes for invalid targets and ports, clap exits with 2 on usage errors
+const EXIT_INVALID_HOSTS: i32 = 3;
+const EXIT_INVALID_PORTS: i32 = 4;
+const EXIT_OUTPUT_FAILED: i32 = 5;
+
+/// Number of most frequently open ports scanned when no ports are given
+const DEFAULT_TOP_PORTS: usize = 100;
+
+/// Finished hosts waiting for the output, the scan pauses when the output falls behind
+const RESULT_CHANNEL_CAPACITY: usize = 64;
+
+/// Hosts probed at the same time during host discovery
+const DISCOVERY_PARALLELISM: usize = 256;
+
+/// Reverse DNS lookups of finished hosts running at the same time
+const NAME_LOOKUP_PARALLELISM: usize = 16;
+
+/// Bounds of --max-rate, --min-rate and --max-host-rate in probes per second
+const MIN_RATE: f64 = 1e-3;
+const MAX_RATE: f64 = 1e6;
+
+/// nmap's options written like short options with two letters, and the long options they stand for
+const NMAP_OPTIONS: &[(&str, &str)] = &[
+    ("-oN", "--oN"),
+    ("-oJ", "--oJ"),
+    ("-oX", "--oX"),
+    ("-oG", "--oG"),
+    ("-oA", "--oA"),
+    ("-iL", "--input-list"),
+];
+
+/// Formats written by `-oA`
+const ALL_OUTPUT_FORMATS: &[Format] = &[Format::Text, Format::Xml, Format::Grepable, Format::Json];
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Host IP or CIDR range to scan, e.g. 192.168.1.1/24
-    hosts: String,
-    /// Ports to scan
-    #[arg(default_value = "20-23,25,80,110,143,194,443,465,587,993")]
-    ports: String,
-    /// Connection timeout in ms
-    #[arg(short, default_value_t = 1000)]
-    timeout_ms: u64,
+    /// Host IPs, CIDR ranges or host names to scan, e.g. 192.168.1.1/24, 2001:db8::/120 or db01.internal
+    #[arg(required_unless_present = "input_list")]
+    hosts: Vec<String>,
+    /// Read targets from a file, one per line, `-` reads from stdin
+    #[arg(short = 'i', long)]
+    input_list: Option<PathBuf>,
+    /// Hosts never to scan, same format as the targets, can be repeated
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Read hosts never to scan from a file, one per line
+    #[arg(long)]
+    exclude_file: Option<PathBuf>,
+    /// Ports or service names to scan, e.g. 22,80,8000-8100 or ssh,http [default: top 100 ports]
+    #[arg(short, long)]
+    ports: Option<String>,
+    /// Scan the given number of most frequently open ports, at most the 165 TCP and 35 UDP ports rmap knows
+    #[arg(long, conflicts_with = "ports")]
+    top_ports: Option<usize>,
+    /// Timing template, 0-5 or paranoid, sneaky, polite, normal, aggressive, insane [default: 3]
+    #[arg(short = 'T', value_enum, value_name = "TEMPLATE", hide_possible_values = true)]
+    timing_template: Option<TimingTemplate>,
+    /// Initial probe timeout in ms, adapts to the measured round trip times of each host [default: 1000]
+    #[arg(short)]
+    timeout_ms: Option<u64>,
+    /// Retransmissions of a probe without answer before giving up on the port [default: 2]
+    #[arg(long)]
+    max_retries: Option<u8>,
+    /// Scan types, `-sS` for a TCP SYN scan, `-sT` for a TCP connect scan, `-sU` for a UDP scan,
+    /// `-sn` for host discovery only [default: T]
+    #[arg(short = 's', value_enum)]
+    scan_types: Vec<ScanType>,
+    /// Host discovery probes, `-PE` ICMP echo, `-PP` ICMP timestamp, `-PM` ICMP address mask,
+    /// `-PS<ports>` TCP SYN, `-PA<ports>` TCP ACK, `-PR` ARP, `-Pn` skips host discovery
+    /// [default: E, S443, A80, P, R]
+    #[arg(short = 'P', value_name = "TYPE")]
+    ping_types: Vec<PingType>,
+    /// Maximum number of probes in flight across all hosts, capped by the open file limit [default: 400]
+    #[arg(long)]
+    max_parallelism: Option<usize>,
+    /// Probes in flight kept when backing off after running out of file descriptors [default: 1]
+    #[arg(long)]
+    min_parallelism: Option<usize>,
+    /// Send at most this many probes per second to all hosts together
+    #[arg(long, value_name = "PPS", value_parser = parse_rate)]
+    max_rate: Option<f64>,
+    /// Send at least this many probes per second, even beyond --max-parallelism
+    #[arg(long, value_name = "PPS", value_parser = parse_rate)]
+    min_rate: Option<f64>,
+    /// Send at most this many probes per second to each host
+    #[arg(long, value_name = "PPS", value_parser = parse_rate)]
+    max_host_rate: Option<f64>,
+    /// Maximum number of hosts whose ports are scanned at the same time [default: 64]
+    #[arg(long)]
+    max_hostgroup: Option<usize>,
+    /// Print hosts in ascending address order, or as soon as they are done
+    /// [default: sorted, completion with --randomize-hosts]
+    #[arg(long, value_enum)]
+    output_order: Option<OutputOrder>,
+    /// Write the text output to the file, also given as -oN. `-` writes to stdout
+    #[arg(long = "oN", value_name = "FILE")]
+    normal_output: Option<PathBuf>,
+    /// Write the results as JSON document to the file, also given as -oJ. `-` writes to stdout
+    #[arg(long = "oJ", value_name = "FILE")]
+    json_output: Option<PathBuf>,
+    /// Write the results as nmap XML to the file, also given as -oX. `-` writes to stdout
+    #[arg(long = "oX", value_name = "FILE")]
+    xml_output: Option<PathBuf>,
+    /// Write the results in nmap's grepable format to the file, also given as -oG. `-` writes to stdout
+    #[arg(long = "oG", value_name = "FILE")]
+    grepable_output: Option<PathBuf>,
+    /// Write the text, XML, grepable and JSON output to BASENAME.nmap, .xml, .gnmap and .json, also given as -oA
+    #[arg(long = "oA", value_name = "BASENAME")]
+    all_outputs: Option<PathBuf>,
+    /// Print one JSON object per line instead of text, for the scan, each host and the stats
+    #[arg(long, default_value_t = false)]
+    ndjson: bool,
+    /// Print a CSV row per port of each host instead of text
+    #[arg(long, default_value_t = false, conflicts_with = "ndjson")]
+    csv: bool,
     /// Show ports also for range scan
-    #[arg(short, long, default_value_t = false)]
+    #[arg(long, default_value_t = false)]
     show_ports: bool,
     /// Omit host name resolution
     #[arg(id = "No DNS resolution", short = 'n', default_value_t = false)]
     no_resolve_hostname: bool,
+    /// Scan hosts in random order instead of ascending order
+    #[arg(long, default_value_t = false)]
+    randomize_hosts: bool,
+    /// Scan ports in the given order instead of a random order
+    #[arg(short = 'r', long, default_value_t = false)]
+    sequential_ports: bool,
+    /// Seed for the random host and port order, makes runs reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Allow IPv6 prefixes with more than 65536 hosts
+    #[arg(long, default_value_t = false)]
+    allow_large_ipv6: bool,
+    /// Also scan network and broadcast addresses of IPv4 CIDR ranges
+    #[arg(long, default_value_t = false)]
+    include_network_broadcast: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ScanType {
+    /// TCP SYN (half-open) scan, needs raw sockets
+    #[value(name = "S")]
+    Syn,
+    /// TCP connect scan
+    #[value(name = "T")]
+    Connect,
+    /// UDP scan
+    #[value(name = "U")]
+    Udp,
+    /// Host discovery only, no port scan
+    #[value(name = "n")]
+    NoPortScan,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputOrder {
+    /// Ascending address order, hosts wait for the hosts before them
+    Sorted,
+    /// Completion order
+    Completion,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum PortState {
-    Open,
-    Closed,
-    Timeout,
+impl ScanType {
+    fn protocol(self) -> Option<Protocol> {
+        match self {
+            ScanType::Syn | ScanType::Connect => Some(Protocol::Tcp),
+            ScanType::Udp => Some(Protocol::Udp),
+            ScanType::NoPortScan => None,
+        }
+    }
 }
 
 #[tokio::main()]
 async fn main() {
-    let cli: Cli = Cli::parse();
+    let start_time = SystemTime::now();
+    let mut args: Vec<OsString> = nmap_args(std::env::args_os()).collect();
+    // `-s` used to be short for --show-ports, it still is without a scan type
+    if let Some(position) = bare_show_ports_option(&args) {
+        eprintln!("warning: -s without a scan type is deprecated, use --show-ports");
+        args[position] = OsString::from("--show-ports");
+    }
+    let mut cli: Cli = Cli::parse_from(args);
+    // Ports used to follow the hosts, like `rmap 10.0.0.1 22,80`. Port
+    // numbers are never valid hosts, so such an argument is taken as ports.
+    let trailing_ports = cli.hosts.len() > 1 && cli.hosts.last().is_some_and(|ports| is_port_numbers(ports));
+    if trailing_ports && cli.ports.is_none() && cli.top_ports.is_none() {
+        cli.ports = cli.hosts.pop();
+        eprintln!("warning: ports after the hosts are deprecated, use -p {}", cli.ports.as_deref().unwrap_or_default());
+    }
 
-    let hosts = expand_hosts(&cli.hosts).expect("No valid host specification");
-    let ports = expand_port_list(&cli.ports);
-    let timeout = cli.timeout_ms;
+    if let Some(conflict) = scan_type_conflict(&cli) {
+        Cli::command().error(ErrorKind::ArgumentConflict, conflict).exit();
+    }
+    let ping_only = cli.scan_types.contains(&ScanType::NoPortScan);
+    let mut timing = timing_policy(&cli);
+    if timing.max_parallelism == 0 || timing.min_parallelism == 0 || timing.max_hostgroup == 0 {
+        Cli::command().error(ErrorKind::ValueValidation, "--max-parallelism, --min-parallelism and --max-hostgroup must be at least 1").exit();
+    }
+    if timing.min_parallelism > timing.max_parallelism {
+        Cli::command().error(ErrorKind::ArgumentConflict, "--min-parallelism cannot exceed --max-parallelism").exit();
+    }
+    if let (Some(min_rate), Some(max_rate)) = (timing.min_rate, timing.max_rate) {
+        if min_rate > max_rate {
+            Cli::command().error(ErrorKind::ArgumentConflict, "--min-rate cannot exceed --max-rate").exit();
+        }
+    }
+    // Randomly ordered hosts could only be sorted once all of them are done
+    let output_order = match (cli.output_order, cli.randomize_hosts) {
+        (Some(OutputOrder::Sorted), true) => {
+            Cli::command().error(ErrorKind::ArgumentConflict, "--output-order sorted cannot be used with --randomize-hosts").exit()
+        }
+        (Some(output_order), _) => output_order,
+        (None, true) => OutputOrder::Completion,
+        (None, false) => OutputOrder::Sorted,
+    };
+    let output_formats = output_formats(&cli);
+    if cli.scan_types.is_empty() {
+        cli.scan_types.push(ScanType::Connect);
+    }
+    let protocols = scan_protocols(&cli.scan_types);
 
-    let show_ports = hosts.clone().count() == 1 || cli.show_ports;
+    if let Some(count) = cli.top_ports {
+        for protocol in &protocols {
+            let known = known_port_count(*protocol);
+            if count > known {
+                eprintln!("warning: --top-ports {count} exceeds the {known} known {protocol} ports, scanning {known}");
+            }
+        }
+    }
+    let ports = match &cli.ports {
+        Some(port_spec) => expand_port_list(port_spec, &protocols).unwrap_or_else(|err| exit_invalid_ports(port_spec, err)),
+        None => protocols.iter()
+            .flat_map(|protocol| {
+                top_ports(cli.top_ports.unwrap_or(DEFAULT_TOP_PORTS), *protocol)
+                    .into_iter()
+                    .map(move |port| (*protocol, port))
+            })
+            .collect(),
+    };
 
-    let scan_result: HashMap<Ipv4Addr, _> = stream::iter(hosts)
-        .map(|host| get_port_states(host, ports.clone(), timeout))
-        .buffer_unordered(20)
-        .collect()
-        .await;
+    // Ports of protocols without a selected scan type, like SCTP, are not scanned
+    let (ports, unscanned_ports): (Vec<_>, Vec<_>) = ports.into_iter()
+        .partition(|(protocol, _)| protocols.contains(protocol));
+    if let Some((protocol, _)) = unscanned_ports.first() {
+        eprintln!("warning: no scan type for {protocol} ports, {} ports are not scanned", unscanned_ports.len());
+    }
+    let seed = cli.seed.unwrap_or_else(random_seed);
+    let mut ports = ports;
+    if !cli.sequential_ports {
+        shuffle(&mut ports, &mut SplitMix64::new(seed));
+    }
+
+    let options = HostSpecOptions {
+        allow_large_ipv6: cli.allow_large_ipv6,
+        skip_network_broadcast: !cli.include_network_broadcast,
+    };
+    let target_list = cli.input_list.as_ref().map(read_target_list).unwrap_or_default();
+    let mut hosts: HostSet = cli.hosts.iter().map(String::as_str)
+        .chain(parse_target_list(&target_list))
+        .map(|host_spec| match expand_hosts(host_spec, options) {
+            Err(NetworkParseError::UnresolvedHost(name)) => {
+                eprintln!("warning: cannot resolve \"{name}\", skipped");
+                HostSet::default()
+            }
+            hosts => hosts.unwrap_or_else(|err| exit_invalid_hosts(host_spec, err)),
+        })
+        .collect();
 
-    let online_scan_results = scan_result.iter().filter_map(|(host, port_states)| {
-        let (open_count, closed_count, timeout_count) = port_statistics_from(port_states);
-        if open_count > 0 || closed_count > 0 {
-            Some((host, port_states, (open_count, closed_count, timeout_count)))
-        } else {
-            None
+    // Failing to resolve an exclusion must not lead to scanning the host
+    let exclude_options = HostSpecOptions { allow_large_ipv6: true, skip_network_broadcast: false };
+    let exclude_list = cli.exclude_file.as_ref().map(read_target_list).unwrap_or_default();
+    hosts.exclude(cli.exclude.iter().map(String::as_str)
+        .chain(parse_target_list(&exclude_list))
+        .map(|host_spec| expand_hosts(host_spec, exclude_options).unwrap_or_else(|err| exit_invalid_hosts(host_spec, err)))
+        .collect());
+
+    if let Err(err) = raise_file_descriptor_limit() {
+        eprintln!("warning: cannot raise the open file limit: {err}");
+    }
+    match file_descriptor_capacity() {
+        Ok(capacity) if capacity < timing.max_parallelism => {
+            eprintln!("warning: the open file limit allows {capacity} probes in flight, lowering --max-parallelism from {}", timing.max_parallelism);
+            timing.max_parallelism = capacity;
+            timing.min_parallelism = timing.min_parallelism.min(capacity);
         }
-    });
+        Ok(_) => {}
+        Err(err) => eprintln!("warning: cannot read the open file limit: {err}"),
+    }
+    let limits = Arc::new(ProbeLimits::new(&timing));
+    let ping_types = if cli.ping_types.is_empty() { PingType::defaults() } else { cli.ping_types.clone() };
+    let discover = !ping_types.contains(&PingType::Skip);
+    let syn_scan = cli.scan_types.contains(&ScanType::Syn);
+    let syn_scanner = (syn_scan || discover).then(|| SynScanner::new(seed, hosts.has_ipv4(), hosts.has_ipv6()).map(Arc::new));
+    let tcp_scan = match &syn_scanner {
+        Some(Ok(scanner)) if syn_scan => TcpScan::Syn(scanner.clone()),
+        Some(Err(err)) if syn_scan => {
+            eprintln!("warning: SYN scan needs raw sockets, {}, falling back to connect scan", raw_socket_error(err));
+            TcpScan::Connect
+        }
+        _ => TcpScan::Connect,
+    };
 
-    let _ = online_scan_results.map(|(host, port_states, (open_count, closed_count, timeout_count))| {
-        let host = if cli.no_resolve_hostname {
-            host.to_string()
-        } else {
-            format!("{host} [{}]", lookup_addr(&IpAddr::from(*host)).unwrap_or_else(|_| { String::new() }))
+    let discovery = discover.then(|| {
+        let tcp_ping = match &syn_scanner {
+            Some(Ok(scanner)) => TcpScan::Syn(scanner.clone()),
+            Some(Err(err)) => {
+                eprintln!("warning: host discovery without raw sockets, {}, connects to the TCP ping ports, skips ARP and \
+                    only sends ICMP echo requests if net.ipv4.ping_group_range allows it", raw_socket_error(err));
+                TcpScan::Connect
+            }
+            None => TcpScan::Connect,
         };
+        let icmp = IcmpPinger::new(seed).ok().map(Arc::new);
+        let arp = ArpScanner::new().ok().map(Arc::new);
+        Arc::new(Discovery::new(ping_types, tcp_ping, icmp, arp, limits.clone()))
+    });
+
+    let show_ports = cli.show_ports || hosts.is_single_host();
+
+    let scan_info = ScanInfo {
+        arguments: std::env::args().collect(),
+        start_time,
+        scan_types: cli.scan_types.iter().map(|scan_type| match (scan_type, &tcp_scan) {
+            (ScanType::Syn, TcpScan::Syn(_)) => "syn",
+            (ScanType::Syn | ScanType::Connect, _) => "connect",
+            (ScanType::Udp, _) => "udp",
+            (ScanType::NoPortScan, _) => "ping",
+        }).collect(),
+        host_discovery: discover,
+        ports: ports.clone(),
+        seed,
+        timing,
+        show_ports,
+    };
+    let mut outputs = Outputs::create(&output_formats, &scan_info).unwrap_or_else(|err| exit_output_failed(err));
+
+    let host_order: Box<dyn Iterator<Item = HostAddr> + Send> = if cli.randomize_hosts {
+        Box::new(hosts.iter_random(seed))
+    } else {
+        Box::new(hosts.iter())
+    };
 
-        println!("{} (open: {}, closed: {}, timeout: {})", host, open_count, closed_count, timeout_count);
-        if show_ports {
-            for (port, port_state) in port_states {
-                println!("    {} : {:?}", port, port_state);
+    let up_hosts = stream::iter(host_order)
+        .map(|host| {
+            let discovery = discovery.clone();
+            async move {
+                let started = SystemTime::now();
+                match discovery {
+                    Some(discovery) => discovery.discover(host, timing.initial_rtt_timeout).await.map(|up| (host, up, started)),
+                    None => Some((host, HostUp::default(), started)),
+                }
+            }
+        });
+    let up_hosts = buffered_in(up_hosts, output_order, DISCOVERY_PARALLELISM).filter_map(future::ready);
+
+    let no_resolve_hostname = cli.no_resolve_hostname;
+    let host_results = up_hosts.map(|(host, up, started)| {
+        let ports = ports.clone();
+        let tcp_scan = tcp_scan.clone();
+        let limits = limits.clone();
+        async move {
+            let (port_states, rtt_stats) = if ping_only {
+                (None, None)
+            } else {
+                let (port_states, rtt_stats) = get_port_states(host, ports, &timing, tcp_scan, up.rtt, limits).await;
+                (Some(port_states), rtt_stats)
+            };
+            HostResult { host, up, port_states, rtt_stats, started, finished: SystemTime::now() }
+        }
+    });
+    let host_results = buffered_in(host_results, output_order, timing.max_hostgroup)
+        .filter(|host_result| future::ready(discover || has_answering_port(host_result)))
+        .map(|host_result| {
+            let hosts = &hosts;
+            async move {
+                let name = host_name(hosts, &host_result.host, no_resolve_hostname).await;
+                (host_result, name)
+            }
+        });
+    let host_results = buffered_in(host_results, output_order, NAME_LOOKUP_PARALLELISM);
+
+    let mut hosts_up = 0;
+    report_while_running(host_results, |(host_result, name)| {
+        hosts_up += 1;
+        outputs.write_host(&host_result, name.as_ref()).unwrap_or_else(|err| exit_output_failed(err));
+        // Nothing else is written once stdout was closed
+        !outputs.is_empty()
+    })
+    .await;
+    if limits.parallelism.limit() < limits.parallelism.max() {
+        eprintln!("warning: ran out of local resources, probes in flight were lowered to {}", limits.parallelism.limit());
+    }
+
+    let end_time = SystemTime::now();
+    let stats = RunStats {
+        end_time,
+        elapsed: end_time.duration_since(start_time).unwrap_or_default(),
+        hosts_total: hosts.host_count(),
+        hosts_up,
+    };
+    outputs.finish(&stats).unwrap_or_else(|err| exit_output_failed(err));
+}
+
+/// Formats and where to write them. The text output goes to stdout unless
+/// another output does.
+fn output_formats(cli: &Cli) -> Vec<(Format, Destination)> {
+    let files = [
+        (Format::Text, &cli.normal_output),
+        (Format::Json, &cli.json_output),
+        (Format::Xml, &cli.xml_output),
+        (Format::Grepable, &cli.grepable_output),
+    ];
+    let mut formats: Vec<(Format, Destination)> = files.iter()
+        .filter_map(|(format, path)| path.as_deref().map(|path| (*format, Destination::from(path))))
+        .collect();
+    if let Some(basename) = &cli.all_outputs {
+        formats.extend(ALL_OUTPUT_FORMATS.iter().map(|format| {
+            let mut path = basename.clone().into_os_string();
+            path.push(".");
+            path.push(format.extension());
+            (*format, Destination::File(path.into()))
+        }));
+    }
+    if cli.ndjson {
+        formats.push((Format::Ndjson, Destination::Stdout));
+    }
+    if cli.csv {
+        formats.push((Format::Csv, Destination::Stdout));
+    }
+    match formats.iter().filter(|(_, destination)| *destination == Destination::Stdout).count() {
+        0 => formats.insert(0, (Format::Text, Destination::Stdout)),
+        1 => {}
+        _ => Cli::command().error(ErrorKind::ArgumentConflict, "only one output can be written to stdout").exit(),
+    }
+    formats
+}
+
+/// Run the futures of the stream, at most `limit` at a time. In sorted
+/// order their results keep the order of the stream, a finished future
+/// waits for the ones before it and keeps counting towards the limit.
+fn buffered_in<S>(stream: S, order: OutputOrder, limit: usize) -> impl Stream<Item = <S::Item as Future>::Output>
+where
+    S: Stream,
+    S::Item: Future,
+{
+    match order {
+        OutputOrder::Sorted => stream.buffered(limit).left_stream(),
+        OutputOrder::Completion => stream.buffer_unordered(limit).right_stream(),
+    }
+}
+
+/// Name the host was given by as target, or else found by reverse lookup
+/// Hands each result to `report` as soon as the stream yields it, while the
+/// stream keeps going. Hosts are handed over as they finish, so memory stays
+/// flat however many hosts are scanned. The stream pauses when `report` falls
+/// behind, and stops once `report` returns false.
+async fn report_while_running<S: Stream>(results: S, mut report: impl FnMut(S::Item) -> bool) {
+    let (sender, mut receiver) = mpsc::channel(RESULT_CHANNEL_CAPACITY);
+    let run = async move {
+        let mut results = pin!(results);
+        while let Some(result) = results.next().await {
+            if sender.send(result).await.is_err() {
+                break;
+            }
+        }
+    };
+    let output = async {
+        while let Some(result) = receiver.recv().await {
+            if !report(result) {
+                receiver.close();
             }
         }
-    }).collect::<Vec<_>>();
+    };
+    future::join(run, output).await;
 }
 
-fn port_statistics_from(port_states: &HashMap<u16, PortState>) -> (u16, u16, u16) {
-    port_states.iter().map(|(_, state)| {
-        match state {
-            PortState::Open => (1, 0, 0),
-            PortState::Closed => (0, 1, 0),
-            PortState::Timeout => (0, 0, 1)
+/// Raw sockets are only denied to processes without CAP_NET_RAW
+fn raw_socket_error(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => format!("{err}, they need CAP_NET_RAW"),
+        _ => err.to_string(),
+    }
+}
+
+/// Without host discovery, hosts only count as up if some port answered
+fn has_answering_port(host_result: &HostResult) -> bool {
+    host_result.port_states.as_ref().is_none_or(|port_states| {
+        port_states.values().any(|port_status| matches!(port_status.state, PortState::Open | PortState::Closed))
+    })
+}
+
+/// The reverse lookup blocks, so it runs on the blocking thread pool
+async fn host_name(hosts: &HostSet, host: &HostAddr, no_resolve_hostname: bool) -> Option<HostName> {
+    match hosts.name(host) {
+        Some(name) => Some(HostName::Target(name.to_string())),
+        None if no_resolve_hostname => None,
+        None => {
+            let ip = host.ip;
+            let name = tokio::task::spawn_blocking(move || lookup_addr(&ip)).await.ok()?.ok()?;
+            // Without a name the address itself is returned
+            Some(name).filter(|name| *name != ip.to_string()).map(HostName::Reverse)
         }
-    }).reduce(|(open_acc, closed_acc, timeout_acc), (open, closed, timeout)| {
-        (open_acc + open, closed_acc + closed, timeout_acc + timeout)
-    }).unwrap()
-}
-
-async fn get_port_states(host: Ipv4Addr, ports: Vec<u16>, timeout: u64) -> (Ipv4Addr, HashMap<u16, PortState>) {
-    let port_states = stream::iter(ports).map(|port| async move {
-        let address = format!("{}:{}", host, port);
-        let port_state = match tokio::time::timeout(Duration::from_millis(timeout),
-                                                    tokio::net::TcpStream::connect(&address)).await {
-            Ok(Ok(_)) => PortState::Open,
-            Ok(Err(_)) => PortState::Closed,
-            Err(_) => PortState::Timeout,
+    }
+}
+
+/// Timing template with the explicitly given values replaced
+/// Protocols of the scan types, each once in the order first given
+fn scan_protocols(scan_types: &[ScanType]) -> Vec<Protocol> {
+    let mut protocols = vec![];
+    for protocol in scan_types.iter().filter_map(|scan_type| scan_type.protocol()) {
+        if !protocols.contains(&protocol) {
+            protocols.push(protocol);
+        }
+    }
+    protocols
+}
+
+/// Scan type options that cannot be combined, `-sn` has no ports to scan
+fn scan_type_conflict(cli: &Cli) -> Option<&'static str> {
+    let ping_only = cli.scan_types.contains(&ScanType::NoPortScan);
+    if cli.scan_types.contains(&ScanType::Syn) && cli.scan_types.contains(&ScanType::Connect) {
+        Some("-sS and -sT cannot be used together")
+    } else if ping_only && cli.scan_types.len() > 1 {
+        Some("-sn cannot be used with port scan types")
+    } else if ping_only && cli.ping_types.contains(&PingType::Skip) {
+        Some("-sn and -Pn cannot be used together")
+    } else if ping_only && (cli.ports.is_some() || cli.top_ports.is_some()) {
+        Some("-sn cannot be used with -p or --top-ports")
+    } else {
+        None
+    }
+}
+
+fn timing_policy(cli: &Cli) -> TimingPolicy {
+    let template = TimingPolicy::from(cli.timing_template.unwrap_or(TimingTemplate::Normal));
+    TimingPolicy {
+        initial_rtt_timeout: cli.timeout_ms.map_or(template.initial_rtt_timeout, Duration::from_millis),
+        max_retries: cli.max_retries.unwrap_or(template.max_retries),
+        max_parallelism: cli.max_parallelism.unwrap_or(template.max_parallelism),
+        min_parallelism: cli.min_parallelism.unwrap_or(template.min_parallelism),
+        max_hostgroup: cli.max_hostgroup.unwrap_or(template.max_hostgroup),
+        max_rate: cli.max_rate.or(template.max_rate),
+        min_rate: cli.min_rate.or(template.min_rate),
+        max_host_rate: cli.max_host_rate.or(template.max_host_rate),
+        ..template
+    }
+}
+
+/// Probes per second, a positive number
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (MIN_RATE..=MAX_RATE).contains(&rate) => Ok(rate),
+        _ => Err(format!("invalid rate \"{rate}\", expected {MIN_RATE} to {MAX_RATE} packets per second")),
+    }
+}
+
+fn random_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() ^ u64::from(now.subsec_nanos()) << 32 ^ u64::from(std::process::id())
+}
+
+/// Comma separated port numbers and ranges, like `20-23,80`
+fn is_port_numbers(ports: &str) -> bool {
+    ports.starts_with(|c: char| c.is_ascii_digit()) && ports.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '-')
+}
+
+/// Position of a `-s` option that is not followed by a scan type
+fn bare_show_ports_option(args: &[OsString]) -> Option<usize> {
+    let options_end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    let is_scan_type = |arg: &OsString| arg.to_str().is_some_and(|arg| ScanType::from_str(arg, false).is_ok());
+    (1..options_end).find(|position| args[*position] == "-s" && !args.get(position + 1).is_some_and(is_scan_type))
+}
+
+/// Rewrite nmap's two letter options like `-oJ` or `-iL` to the long options clap parses,
+/// also with the file attached like `-oJscan.json` or `-oJ=scan.json`
+fn nmap_args(args: impl Iterator<Item = OsString>) -> impl Iterator<Item = OsString> {
+    let mut options_end = false;
+    args.map(move |arg| {
+        options_end |= arg == "--";
+        let arg_str = match arg.to_str() {
+            Some(arg_str) if !options_end => arg_str,
+            _ => return arg,
         };
-        (port, port_state)
-    }).buffer_unordered(20).collect().await;
-    (host, port_states)
-}
\ No newline at end of file
+        let option = NMAP_OPTIONS.iter().find(|(option, _)| arg_str.starts_with(option));
+        match option.map(|(option, long)| (long, &arg_str[option.len()..])) {
+            Some((long, "")) => OsString::from(long),
+            Some((long, file)) => OsString::from(format!("{long}={}", file.strip_prefix('=').unwrap_or(file))),
+            None => arg,
+        }
+    })
+}
+
+fn exit_output_failed(err: OutputError) -> ! {
+    eprintln!("error: {err}");
+    std::process::exit(EXIT_OUTPUT_FAILED);
+}
+
+fn exit_invalid_hosts(host_spec: &str, err: NetworkParseError) -> ! {
+    eprintln!("error: invalid host specification \"{host_spec}\": {err}");
+    std::process::exit(EXIT_INVALID_HOSTS);
+}
+
+fn exit_invalid_ports(port_spec: &str, err: NetworkParseError) -> ! {
+    eprintln!("error: invalid port specification: {err}");
+    if let Some((position, len)) = err.port_span() {
+        let indent = port_spec[..position].chars().count();
+        let marker = port_spec[position..position + len].chars().count().max(1);
+        eprintln!("    {port_spec}");
+        eprintln!("    {}{}", " ".repeat(indent), "^".repeat(marker));
+    }
+    std::process::exit(EXIT_INVALID_PORTS);
+}
+
+fn read_target_list(path: &PathBuf) -> String {
+    let mut target_list = String::new();
+    let read = if path.as_os_str() == "-" {
+        std::io::stdin().read_to_string(&mut target_list).map(|_| target_list)
+    } else {
+        std::fs::read_to_string(path)
+    };
+    read.unwrap_or_else(|err| {
+        eprintln!("error: cannot read targets from {}: {err}", path.display());
+        std::process::exit(EXIT_INVALID_HOSTS);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nmap_output_args() {
+        let args = ["rmap", "-oX", "scan.xml", "-oJscan.json", "-oG=-", "-o", "--", "-oN"];
+        let args: Vec<OsString> = nmap_args(args.iter().map(OsString::from)).collect();
+        assert_eq!(args, ["rmap", "--oX", "scan.xml", "--oJ=scan.json", "--oG=-", "-o", "--", "-oN"]);
+    }
+
+    #[test]
+    fn test_nmap_input_list_arg() {
+        let args = ["rmap", "-iL", "targets.txt", "-iLmore.txt", "-i", "list.txt"];
+        let args: Vec<OsString> = nmap_args(args.iter().map(OsString::from)).collect();
+        assert_eq!(args, ["rmap", "--input-list", "targets.txt", "--input-list=more.txt", "-i", "list.txt"]);
+        let cli = Cli::parse_from(nmap_args(["rmap", "-iL", "targets.txt"].iter().map(OsString::from)));
+        assert_eq!(cli.input_list, Some(PathBuf::from("targets.txt")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buffered_in_order() {
+        // Ascending hosts that finish out of order
+        let hosts = || stream::iter(vec![(1, 3), (2, 1), (3, 2)]).map(|(host, delay)| async move {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            host
+        });
+        let sorted: Vec<_> = buffered_in(hosts(), OutputOrder::Sorted, 3).collect().await;
+        assert_eq!(sorted, [1, 2, 3]);
+        let completion: Vec<_> = buffered_in(hosts(), OutputOrder::Completion, 3).collect().await;
+        assert_eq!(completion, [2, 3, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_results_are_reported_while_running() {
+        let start = tokio::time::Instant::now();
+        let results = stream::iter(vec![1, 5]).then(|delay| async move {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            delay
+        });
+        let mut reported = vec![];
+        report_while_running(results, |delay| {
+            reported.push((delay, start.elapsed()));
+            true
+        })
+        .await;
+        // The first result is reported before the last one is done
+        assert_eq!(reported, [(1, Duration::from_secs(1)), (5, Duration::from_secs(6))]);
+
+        // An endless stream stops once nothing is reported anymore
+        report_while_running(stream::iter(0..), |_| false).await;
+    }
+
+    #[test]
+    fn test_parse_rate_bounds() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert_eq!(parse_rate("0.001"), Ok(0.001));
+        assert_eq!(parse_rate("1000000"), Ok(1e6));
+        for rate in &["1e-20", "0", "-1", "1e7", "inf", "NaN", "fast"] {
+            assert!(parse_rate(rate).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn test_scan_protocols_are_unique() {
+        let scan_types = [ScanType::Udp, ScanType::Connect, ScanType::Udp];
+        assert_eq!(scan_protocols(&scan_types), [Protocol::Udp, Protocol::Tcp]);
+        assert!(scan_protocols(&[ScanType::NoPortScan]).is_empty());
+    }
+
+    #[test]
+    fn test_ping_scan_conflicts_with_ports() {
+        let cli = Cli::parse_from(["rmap", "-sn", "-p", "ssh", "192.0.2.1"]);
+        assert_eq!(scan_type_conflict(&cli), Some("-sn cannot be used with -p or --top-ports"));
+        let cli = Cli::parse_from(["rmap", "-sn", "--top-ports", "10", "192.0.2.1"]);
+        assert!(scan_type_conflict(&cli).is_some());
+        let cli = Cli::parse_from(["rmap", "-sn", "192.0.2.1"]);
+        assert_eq!(scan_type_conflict(&cli), None);
+    }
+}
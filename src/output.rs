@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::csv::CsvWriter;
+use crate::grepable::GrepableWriter;
+use crate::hosts::HostName;
+use crate::json::{JsonWriter, NdjsonWriter};
+use crate::scan::HostResult;
+use crate::services::Protocol;
+use crate::text::TextWriter;
+use crate::timing::TimingPolicy;
+use crate::xml::XmlWriter;
+
+/// Parameters of a scan, reported before its hosts
+#[derive(Clone, Debug)]
+pub struct ScanInfo {
+    pub arguments: Vec<String>,
+    pub start_time: SystemTime,
+    /// `syn`, `connect` and `udp`, or `ping` for host discovery only
+    pub scan_types: Vec<&'static str>,
+    pub host_discovery: bool,
+    pub ports: Vec<(Protocol, u16)>,
+    pub seed: u64,
+    pub timing: TimingPolicy,
+    /// Show the ports of every host in the text output
+    pub show_ports: bool,
+}
+
+/// Totals of a scan, reported after its hosts
+#[derive(Clone, Debug)]
+pub struct RunStats {
+    pub end_time: SystemTime,
+    pub elapsed: Duration,
+    /// Hosts of the targets after exclusions
+    pub hosts_total: u128,
+    /// Hosts in the results
+    pub hosts_up: u64,
+}
+
+/// A format the results are written in. The header is written when the
+/// output is created, each host as soon as it is reported.
+pub trait Output {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()>;
+    fn finish(self: Box<Self>, stats: &RunStats) -> io::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Ndjson,
+    Xml,
+    Grepable,
+    Csv,
+}
+
+impl Format {
+    /// File name extension of `-oA`, nmap's for the formats it has
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "nmap",
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Xml => "xml",
+            Format::Grepable => "gnmap",
+            Format::Csv => "csv",
+        }
+    }
+
+    fn create(self, writer: Box<dyn Write>, scan: &ScanInfo) -> io::Result<Box<dyn Output>> {
+        Ok(match self {
+            Format::Text => Box::new(TextWriter::new(writer, scan.show_ports)),
+            Format::Json => Box::new(JsonWriter::new(writer, scan)?),
+            Format::Ndjson => Box::new(NdjsonWriter::new(writer, scan)?),
+            Format::Xml => Box::new(XmlWriter::new(writer, scan)?),
+            Format::Grepable => Box::new(GrepableWriter::new(writer, scan)?),
+            Format::Csv => Box::new(CsvWriter::new(writer)?),
+        })
+    }
+}
+
+/// Where an output is written, `-` as file name means stdout like in nmap
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Stdout,
+    File(PathBuf),
+}
+
+impl From<&Path> for Destination {
+    fn from(path: &Path) -> Self {
+        match path.as_os_str() == "-" {
+            true => Destination::Stdout,
+            false => Destination::File(path.to_path_buf()),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Stdout => write!(f, "stdout"),
+            Destination::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// An output that could not be written
+#[derive(Debug)]
+pub struct OutputError {
+    pub destination: Destination,
+    pub err: io::Error,
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot write output to {}: {}", self.destination, self.err)
+    }
+}
+
+/// All outputs of a scan. Stdout closed by the reader, like `rmap ... | head`,
+/// only ends the output to stdout.
+pub struct Outputs {
+    outputs: Vec<(Destination, Box<dyn Output>)>,
+}
+
+impl Outputs {
+    pub fn create(formats: &[(Format, Destination)], scan: &ScanInfo) -> Result<Self, OutputError> {
+        let outputs = formats.iter().map(|(format, destination)| {
+            let writer: io::Result<Box<dyn Write>> = match destination {
+                Destination::Stdout => Ok(Box::new(io::stdout())),
+                Destination::File(path) => File::create(path).map(|file| Box::new(BufWriter::new(file)) as Box<dyn Write>),
+            };
+            writer
+                .and_then(|writer| format.create(writer, scan))
+                .map(|output| (destination.clone(), output))
+                .map_err(|err| OutputError { destination: destination.clone(), err })
+        });
+        let mut created = Outputs { outputs: vec![] };
+        for output in outputs {
+            match output {
+                Ok(output) => created.outputs.push(output),
+                Err(err) if is_closed_stdout(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(created)
+    }
+
+    pub fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> Result<(), OutputError> {
+        let mut failed = Ok(());
+        self.outputs.retain_mut(|(destination, output)| match output.write_host(result, name) {
+            Ok(()) => true,
+            Err(err) => {
+                let err = OutputError { destination: destination.clone(), err };
+                if !is_closed_stdout(&err) && failed.is_ok() {
+                    failed = Err(err);
+                }
+                false
+            }
+        });
+        failed
+    }
+
+    pub fn finish(self, stats: &RunStats) -> Result<(), OutputError> {
+        for (destination, output) in self.outputs {
+            if let Err(err) = output.finish(stats) {
+                let err = OutputError { destination, err };
+                if !is_closed_stdout(&err) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All outputs ended, like after stdout was closed
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+}
+
+fn is_closed_stdout(err: &OutputError) -> bool {
+    err.destination == Destination::Stdout && err.err.kind() == io::ErrorKind::BrokenPipe
+}
+
+/// Ports by protocol as comma separated ascending ranges, like `22,80-81`
+pub fn port_ranges(ports: &[(Protocol, u16)]) -> BTreeMap<Protocol, String> {
+    let mut by_protocol: BTreeMap<Protocol, Vec<u16>> = BTreeMap::new();
+    for (protocol, port) in ports {
+        by_protocol.entry(*protocol).or_default().push(*port);
+    }
+    by_protocol.into_iter().map(|(protocol, mut ports)| {
+        ports.sort_unstable();
+        ports.dedup();
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for port in ports {
+            match ranges.last_mut() {
+                Some((_, last)) if last.checked_add(1) == Some(port) => *last = port,
+                _ => ranges.push((port, port)),
+            }
+        }
+        let ranges: Vec<String> = ranges.into_iter().map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{first}-{last}"),
+        }).collect();
+        (protocol, ranges.join(","))
+    }).collect()
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Local time like nmap's, `Sat Oct 18 14:05:09 2026`
+pub fn time_string(time: SystemTime) -> String {
+    let seconds = libc::time_t::try_from(unix_seconds(time)).unwrap_or(libc::time_t::MAX);
+    // SAFETY: an all zero tm is valid, localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the call
+    if unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        return String::new();
+    }
+    let mut buffer = [0u8; 64];
+    // SAFETY: strftime writes at most buffer.len() bytes and returns the length without the NUL
+    let len = unsafe { libc::strftime(buffer.as_mut_ptr().cast(), buffer.len(), b"%a %b %e %H:%M:%S %Y\0".as_ptr().cast(), &tm) };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// Scan of 192.0.2.1 shared by the tests of the output formats, which
+/// change the fields their output depends on
+#[cfg(test)]
+pub mod fixtures {
+    use std::net::IpAddr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::discovery::HostUp;
+    use crate::hosts::HostAddr;
+    use crate::scan::{PortState, PortStatus, Reason};
+
+    pub fn scan_info() -> ScanInfo {
+        ScanInfo {
+            arguments: vec!["rmap".to_string(), "192.0.2.1".to_string()],
+            start_time: UNIX_EPOCH + Duration::from_secs(1000),
+            scan_types: vec!["connect"],
+            host_discovery: true,
+            ports: vec![(Protocol::Tcp, 80), (Protocol::Tcp, 22), (Protocol::Tcp, 81)],
+            seed: 7,
+            timing: TimingPolicy::default(),
+            show_ports: false,
+        }
+    }
+
+    pub fn host_result() -> HostResult {
+        let port_states = vec![((Protocol::Tcp, 22), PortStatus::new(PortState::Open, Reason::SynAck))];
+        HostResult {
+            host: HostAddr::from(IpAddr::from([192, 0, 2, 1])),
+            up: HostUp { mac: None, rtt: Some(Duration::from_millis(2)), reason: Some(Reason::SynAck) },
+            port_states: Some(port_states.into_iter().collect()),
+            rtt_stats: None,
+            started: UNIX_EPOCH + Duration::from_millis(1_000_500),
+            finished: UNIX_EPOCH + Duration::from_secs(1001),
+        }
+    }
+
+    pub fn run_stats() -> RunStats {
+        RunStats { end_time: UNIX_EPOCH + Duration::from_secs(1002), elapsed: Duration::from_secs(2), hosts_total: 1, hosts_up: 1 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_ranges() {
+        let ports = [(Protocol::Udp, 53), (Protocol::Tcp, 3), (Protocol::Tcp, 1), (Protocol::Tcp, 2), (Protocol::Tcp, 80)];
+        let ranges = port_ranges(&ports);
+        assert_eq!(ranges[&Protocol::Tcp], "1-3,80");
+        assert_eq!(ranges[&Protocol::Udp], "53");
+    }
+
+    #[test]
+    fn test_destination() {
+        assert_eq!(Destination::from(Path::new("-")), Destination::Stdout);
+        assert_eq!(Destination::from(Path::new("scan.xml")), Destination::File(PathBuf::from("scan.xml")));
+    }
+}
@@ -0,0 +1,235 @@
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+/// File descriptors kept free for standard streams, raw sockets, the
+/// runtime and name resolution
+const RESERVED_FILE_DESCRIPTORS: usize = 32;
+
+/// Running out of resources once makes many probes fail at the same time,
+/// they count as one event
+const BACK_OFF_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Limit on the probes in flight across all hosts. Each probe holds a
+/// permit of one shared semaphore. When probes run out of file descriptors
+/// or buffers the limit is halved, down to the minimum, and it grows back by
+/// one after as many successful probes as the limit. With a minimum rate a
+/// waiting probe goes ahead without a permit when no probe was started for
+/// longer than the rate allows.
+pub struct Parallelism {
+    semaphore: Semaphore,
+    min: usize,
+    max: usize,
+    state: Mutex<ParallelismState>,
+    min_rate: Option<MinRate>,
+}
+
+struct MinRate {
+    interval: Duration,
+    last_start: Mutex<Instant>,
+}
+
+struct ParallelismState {
+    limit: usize,
+    /// Permits to forget when they are returned, after the limit was
+    /// lowered below the number of probes in flight
+    debt: usize,
+    successes: usize,
+    last_back_off: Option<Instant>,
+}
+
+/// Held while a probe is in flight, probes started for the minimum rate
+/// have no permit
+pub struct ProbePermit<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    parallelism: &'a Parallelism,
+}
+
+impl Parallelism {
+    /// `min_rate` in probes per second
+    pub fn new(min: usize, max: usize, min_rate: Option<f64>) -> Self {
+        let max = max.max(1);
+        let min = min.clamp(1, max);
+        Parallelism {
+            semaphore: Semaphore::new(max),
+            min,
+            max,
+            state: Mutex::new(ParallelismState { limit: max, debt: 0, successes: 0, last_back_off: None }),
+            min_rate: min_rate.map(|min_rate| MinRate {
+                interval: Duration::from_secs_f64(1.0 / min_rate),
+                last_start: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) -> ProbePermit<'_> {
+        let acquire = async { Some(self.semaphore.acquire().await.expect("the semaphore is never closed")) };
+        let permit = match &self.min_rate {
+            Some(min_rate) => tokio::select! {
+                permit = acquire => permit,
+                () = min_rate.overdue() => None,
+            },
+            None => acquire.await,
+        };
+        if let Some(min_rate) = &self.min_rate {
+            *min_rate.last_start.lock().unwrap() = Instant::now();
+        }
+        ProbePermit { permit, parallelism: self }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// A probe failed for lack of local resources
+    pub fn back_off(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.last_back_off.is_some_and(|last_back_off| last_back_off.elapsed() < BACK_OFF_INTERVAL) {
+            return;
+        }
+        state.last_back_off = Some(Instant::now());
+        state.successes = 0;
+        let limit = (state.limit / 2).max(self.min);
+        let excess = state.limit - limit;
+        state.limit = limit;
+        state.debt += excess - self.semaphore.forget_permits(excess);
+    }
+
+    /// A probe completed without a local error
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.limit >= self.max {
+            return;
+        }
+        state.successes += 1;
+        if state.successes >= state.limit {
+            state.successes = 0;
+            state.limit += 1;
+            if state.debt > 0 {
+                state.debt -= 1;
+            } else {
+                self.semaphore.add_permits(1);
+            }
+        }
+    }
+}
+
+impl MinRate {
+    /// Returns when no probe was started for an interval, to one waiting probe
+    async fn overdue(&self) {
+        loop {
+            let due = *self.last_start.lock().unwrap() + self.interval;
+            tokio::time::sleep_until(due).await;
+            let mut last_start = self.last_start.lock().unwrap();
+            if last_start.elapsed() >= self.interval {
+                *last_start = Instant::now();
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for ProbePermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.parallelism.state.lock().unwrap();
+        if let (Some(permit), true) = (self.permit.take(), state.debt > 0) {
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+}
+
+/// Raise the soft limit on open files to the hard limit
+pub fn raise_file_descriptor_limit() -> io::Result<()> {
+    let limit = open_file_limit()?;
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit { rlim_cur: limit.rlim_max, rlim_max: limit.rlim_max };
+        // SAFETY: setrlimit only reads the given struct
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Number of probes that fit into the soft limit on open files
+pub fn file_descriptor_capacity() -> io::Result<usize> {
+    let open_files = usize::try_from(open_file_limit()?.rlim_cur).unwrap_or(usize::MAX);
+    Ok(open_files.saturating_sub(RESERVED_FILE_DESCRIPTORS).max(1))
+}
+
+fn open_file_limit() -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes to the given struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_back_off_halves_limit_down_to_minimum() {
+        let parallelism = Parallelism::new(2, 8, None);
+        let permits: Vec<_> = futures::future::join_all((0..6).map(|_| parallelism.acquire())).await;
+        parallelism.back_off();
+        assert_eq!(parallelism.limit(), 4);
+        // Two permits were free, the other two are forgotten when returned
+        assert_eq!(parallelism.semaphore.available_permits(), 0);
+        drop(permits);
+        assert_eq!(parallelism.semaphore.available_permits(), 4);
+
+        // Back offs in quick succession count once
+        parallelism.back_off();
+        assert_eq!(parallelism.limit(), 4);
+        parallelism.state.lock().unwrap().last_back_off = None;
+        parallelism.back_off();
+        parallelism.state.lock().unwrap().last_back_off = None;
+        parallelism.back_off();
+        assert_eq!(parallelism.limit(), 2);
+        assert_eq!(parallelism.semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_limit_grows_back_after_successes() {
+        let parallelism = Parallelism::new(1, 4, None);
+        parallelism.back_off();
+        assert_eq!(parallelism.limit(), 2);
+        parallelism.record_success();
+        assert_eq!(parallelism.limit(), 2);
+        parallelism.record_success();
+        assert_eq!(parallelism.limit(), 3);
+        (0..20).for_each(|_| parallelism.record_success());
+        assert_eq!(parallelism.limit(), 4);
+        assert_eq!(parallelism.semaphore.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_min_rate_starts_probes_without_permit() {
+        let parallelism = Parallelism::new(1, 1, Some(100.0));
+        let first = parallelism.acquire().await;
+        assert!(first.permit.is_some());
+        let started = Instant::now();
+        let second = parallelism.acquire().await;
+        assert!(second.permit.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(9));
+        drop(second);
+        assert_eq!(parallelism.semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_file_descriptor_capacity() {
+        assert!(file_descriptor_capacity().unwrap() >= 1);
+    }
+}
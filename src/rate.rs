@@ -0,0 +1,75 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Share of a second whose probes may be sent at once. Timers only wake
+/// up about every millisecond, high rates send the probes of a wake up together.
+const BURST_SECONDS: f64 = 0.01;
+
+/// Token bucket limiting probes to a rate in packets per second. Every
+/// probe reserves the next free send time, so the rate holds over any
+/// period however late the timers wake up, with at most one burst of
+/// probes above it.
+pub struct RateLimiter {
+    interval: Duration,
+    /// Time the bucket may fill up for
+    burst: Duration,
+    next_send: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(packets_per_second: f64) -> Self {
+        let interval = Duration::from_secs_f64(1.0 / packets_per_second);
+        let burst_size = (packets_per_second * BURST_SECONDS).floor().max(1.0);
+        RateLimiter { interval, burst: interval.mul_f64(burst_size - 1.0), next_send: Mutex::new(None) }
+    }
+
+    /// Wait for the turn of the next probe
+    pub async fn wait(&self) {
+        let send_at = {
+            let mut next_send = self.next_send.lock().unwrap();
+            let now = Instant::now();
+            let earliest = now.checked_sub(self.burst).unwrap_or(now);
+            let send_at = next_send.map_or(now, |next_send| next_send.max(earliest));
+            *next_send = Some(send_at + self.interval);
+            send_at
+        };
+        tokio::time::sleep_until(send_at).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_low_rate_spaces_probes() {
+        let rate = RateLimiter::new(20.0);
+        let start = Instant::now();
+        for _ in 0..5 {
+            rate.wait().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_high_rate_sends_in_bursts() {
+        let rate = RateLimiter::new(100_000.0);
+        let start = Instant::now();
+        // The bucket only fills up while probes are not sent
+        for _ in 0..3000 {
+            rate.wait().await;
+        }
+        // Timers wake up on whole milliseconds
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_micros(29_990), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(31), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_burst_size() {
+        assert_eq!(RateLimiter::new(1.0).burst, Duration::ZERO);
+        assert_eq!(RateLimiter::new(1000.0).burst, Duration::from_millis(9));
+    }
+}
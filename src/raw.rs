@@ -0,0 +1,189 @@
+use std::convert::TryInto;
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+use crate::hosts::HostAddr;
+
+pub const ICMP_DEST_UNREACH: u8 = 3;
+pub const ICMPV6_DEST_UNREACH: u8 = 1;
+
+/// Non-blocking raw socket registered with the tokio runtime, fails without CAP_NET_RAW
+pub fn raw_socket(domain: Domain, protocol: Protocol) -> io::Result<AsyncFd<Socket>> {
+    let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    // SAFETY: the socket owns its file descriptor and is only closed by dropping the AsyncFd
+    Ok(unsafe { AsyncFd::register(socket)? })
+}
+
+pub async fn send_to(socket: &AsyncFd<Socket>, packet: &[u8], target: &SockAddr) -> io::Result<()> {
+    loop {
+        let mut guard = socket.writable().await?;
+        match guard.try_io(|socket| socket.get_ref().send_to(packet, target)) {
+            Ok(result) => return result.map(|_| ()),
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Receive the next packet into `buffer`, returns the received part of it
+pub async fn recv_from<'a>(socket: &AsyncFd<Socket>, buffer: &'a mut [MaybeUninit<u8>]) -> io::Result<(&'a [u8], SockAddr)> {
+    loop {
+        let mut guard = socket.readable().await?;
+        match guard.try_io(|socket| socket.get_ref().recv_from(buffer)) {
+            Ok(Ok((len, from))) => {
+                // SAFETY: recv_from initialized the first `len` bytes
+                let packet = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, len) };
+                return Ok((packet, from));
+            }
+            Ok(Err(err)) => return Err(err),
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Errors after which receiving again can succeed, listeners stop on others
+pub fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+}
+
+/// Queue the ICMP errors about the packets of the socket, to be read with
+/// `queued_unreachable_code` once the socket reports an error
+pub fn set_recv_err(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 { (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) } else { (libc::IPPROTO_IP, libc::IP_RECVERR) };
+    let enable: libc::c_int = 1;
+    let len = mem::size_of_val(&enable) as libc::socklen_t;
+    if unsafe { libc::setsockopt(socket.as_raw_fd(), level, name, (&enable as *const libc::c_int).cast(), len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Code of the ICMP destination unreachable message behind the error of a
+/// socket with `set_recv_err`. The error number alone does not tell them
+/// apart, Linux reports administratively prohibited as EHOSTUNREACH.
+pub fn queued_unreachable_code(socket: &impl AsRawFd) -> Option<u8> {
+    // u64 keeps the control messages aligned
+    let mut control = [0_u64; 32];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = mem::size_of_val(&control) as _;
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) } < 0 {
+        return None;
+    }
+    let mut header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    while let Some(control_message) = unsafe { header.as_ref() } {
+        let unreachable = match (control_message.cmsg_level, control_message.cmsg_type) {
+            (libc::SOL_IP, libc::IP_RECVERR) => Some((libc::SO_EE_ORIGIN_ICMP, ICMP_DEST_UNREACH)),
+            (libc::SOL_IPV6, libc::IPV6_RECVERR) => Some((libc::SO_EE_ORIGIN_ICMP6, ICMPV6_DEST_UNREACH)),
+            _ => None,
+        };
+        if let Some(unreachable) = unreachable {
+            // SAFETY: the kernel puts a sock_extended_err into these messages
+            let err = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::sock_extended_err>()) };
+            if (err.ee_origin, err.ee_type) == unreachable {
+                return Some(err.ee_code);
+            }
+        }
+        header = unsafe { libc::CMSG_NXTHDR(&message, header) };
+    }
+    None
+}
+
+/// Source address and payload of an IPv4 packet, raw IPv4 sockets receive
+/// the IP header while IPv6 raw sockets only receive the payload
+pub fn ipv4_payload(packet: &[u8]) -> Option<(IpAddr, &[u8])> {
+    let header_len = usize::from(packet.first()? & 0x0f) * 4;
+    let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    Some((IpAddr::from(source), packet.get(header_len..)?))
+}
+
+/// Local address the operating system routes packets to a host from. It is
+/// looked up with the first probe to the host and reused by the others, a
+/// failed lookup is tried again with the next probe.
+pub struct Route {
+    pub host: HostAddr,
+    source: Mutex<Option<IpAddr>>,
+}
+
+impl Route {
+    pub fn new(host: HostAddr) -> Self {
+        Route { host, source: Mutex::new(None) }
+    }
+
+    pub fn source(&self) -> io::Result<IpAddr> {
+        let mut source = self.source.lock().unwrap();
+        if let Some(source) = *source {
+            return Ok(source);
+        }
+        let unspecified = match self.host.ip {
+            IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let socket = UdpSocket::bind(unspecified)?;
+        // Connecting a UDP socket sends nothing, it only picks the route
+        socket.connect(self.host.socket_addr(9))?;
+        let local = socket.local_addr()?.ip();
+        *source = Some(local);
+        Ok(local)
+    }
+}
+
+/// Internet checksum (RFC 1071)
+pub fn internet_checksum<'a>(bytes: impl Iterator<Item = &'a u8>) -> u16 {
+    let mut sum = 0_u32;
+    let mut high = None;
+    for byte in bytes {
+        match high.take() {
+            None => high = Some(*byte),
+            Some(high) => sum += u32::from(u16::from_be_bytes([high, *byte])),
+        }
+    }
+    if let Some(high) = high {
+        sum += u32::from(high) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internet_checksum() {
+        // Example from RFC 1071
+        let bytes = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(internet_checksum(bytes.iter()), !0xddf2);
+        assert_eq!(internet_checksum([0xff].iter()), !0xff00);
+    }
+
+    #[test]
+    fn test_queued_port_unreachable() {
+        let closed = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_recv_err(&socket, false).unwrap();
+        socket.connect(closed.local_addr().unwrap()).unwrap();
+        drop(closed);
+        assert_eq!(queued_unreachable_code(&socket), None);
+        socket.send(b"probe").unwrap();
+        let err = socket.recv(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(queued_unreachable_code(&socket), Some(3));
+    }
+
+    #[test]
+    fn test_route_source() {
+        let route = Route::new(HostAddr::from(IpAddr::from(Ipv4Addr::LOCALHOST)));
+        assert_eq!(route.source().unwrap(), Ipv4Addr::LOCALHOST);
+        assert_eq!(*route.source.lock().unwrap(), Some(Ipv4Addr::LOCALHOST.into()));
+        assert_eq!(route.source().unwrap(), Ipv4Addr::LOCALHOST);
+    }
+}
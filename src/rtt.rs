@@ -0,0 +1,101 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::timing::TimingPolicy;
+
+/// Retransmission timeout of one host, estimated from the round trip times
+/// of its answers like TCP does (Jacobson/Karels, RFC 6298) and like nmap
+/// does for its probes. Until the first answer the initial timeout is used,
+/// afterwards the timeout stays within the bounds of the timing policy.
+pub struct RttEstimator {
+    state: Mutex<RttState>,
+}
+
+/// Round trip times measured for a host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RttStats {
+    pub srtt: Duration,
+    pub rttvar: Duration,
+    pub timeout: Duration,
+}
+
+struct RttState {
+    initial_timeout: Duration,
+    min_timeout: Duration,
+    max_timeout: Duration,
+    /// Smoothed round trip time and its variation, `None` without answers
+    smoothed: Option<(Duration, Duration)>,
+}
+
+impl RttEstimator {
+    pub fn new(timing: &TimingPolicy) -> Self {
+        RttEstimator {
+            state: Mutex::new(RttState {
+                initial_timeout: timing.initial_rtt_timeout,
+                min_timeout: timing.min_rtt_timeout,
+                max_timeout: timing.max_rtt_timeout,
+                smoothed: None,
+            }),
+        }
+    }
+
+    /// Add a round trip time. Only answers to probes that were sent once
+    /// may be measured, an answer to a retransmitted probe could belong to
+    /// either transmission (Karn's algorithm).
+    pub fn update(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.smoothed = Some(match state.smoothed {
+            None => (rtt, rtt / 2),
+            Some((srtt, rttvar)) => {
+                let deviation = srtt.abs_diff(rtt);
+                (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + deviation / 4)
+            }
+        });
+    }
+
+    pub fn timeout(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        match state.smoothed {
+            Some((srtt, rttvar)) => (srtt + rttvar * 4).clamp(state.min_timeout, state.max_timeout.max(state.min_timeout)),
+            None => state.initial_timeout,
+        }
+    }
+
+    /// `None` until the first round trip time was measured
+    pub fn stats(&self) -> Option<RttStats> {
+        let (srtt, rttvar) = self.state.lock().unwrap().smoothed?;
+        Some(RttStats { srtt, rttvar, timeout: self.timeout() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_follows_round_trip_times() {
+        let rtt = RttEstimator::new(&TimingPolicy::default());
+        assert_eq!(rtt.timeout(), Duration::from_secs(1));
+        assert_eq!(rtt.stats(), None);
+        rtt.update(Duration::from_millis(200));
+        // 200 ms + 4 * 100 ms
+        assert_eq!(rtt.timeout(), Duration::from_millis(600));
+        let stats = RttStats { srtt: Duration::from_millis(200), rttvar: Duration::from_millis(100), timeout: Duration::from_millis(600) };
+        assert_eq!(rtt.stats(), Some(stats));
+        rtt.update(Duration::from_millis(200));
+        assert_eq!(rtt.timeout(), Duration::from_millis(500));
+        (0..50).for_each(|_| rtt.update(Duration::from_millis(200)));
+        assert!(rtt.timeout() < Duration::from_millis(210));
+    }
+
+    #[test]
+    fn test_timeout_is_clamped() {
+        let timing = TimingPolicy { initial_rtt_timeout: Duration::from_millis(10), ..TimingPolicy::default() };
+        let rtt = RttEstimator::new(&timing);
+        assert_eq!(rtt.timeout(), Duration::from_millis(10));
+        rtt.update(Duration::from_micros(50));
+        assert_eq!(rtt.timeout(), timing.min_rtt_timeout);
+        rtt.update(Duration::from_secs(60));
+        assert_eq!(rtt.timeout(), timing.max_rtt_timeout);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::stream;
+use futures::StreamExt;
+use socket2::{Domain, Socket, Type};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::discovery::HostUp;
+use crate::hosts::HostAddr;
+use crate::parallelism::{Parallelism, ProbePermit};
+use crate::rate::RateLimiter;
+use crate::raw::{queued_unreachable_code, set_recv_err, Route};
+use crate::rtt::{RttEstimator, RttStats};
+use crate::services::Protocol;
+use crate::syn::SynScanner;
+use crate::timing::TimingPolicy;
+use crate::udp::{udp_probe, SendDelay};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortState {
+    Open,
+    Closed,
+    /// No answer, or the probe was rejected by a firewall
+    Filtered,
+    /// No answer to any UDP probe, the port is open or the probes are dropped
+    OpenFiltered,
+    /// The host or its network cannot be reached, the port was not probed
+    Unreachable,
+    /// The probe could not be sent, e.g. without free file descriptors.
+    /// Says nothing about the port.
+    LocalError,
+}
+
+impl PortState {
+    /// Lower case name, as in nmap's machine readable output
+    pub fn name(self) -> &'static str {
+        match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::OpenFiltered => "open|filtered",
+            PortState::Unreachable => "unreachable",
+            PortState::LocalError => "local-error",
+        }
+    }
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortState::OpenFiltered => write!(f, "Open|Filtered"),
+            PortState::LocalError => write!(f, "Local-Error"),
+            port_state => write!(f, "{port_state:?}"),
+        }
+    }
+}
+
+/// Why a port is in its state, named like nmap's `--reason` output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// SYN/ACK to a raw SYN, or an accepted connection
+    SynAck,
+    ConnRefused,
+    /// RST to a raw probe
+    Reset,
+    UdpResponse,
+    PortUnreach,
+    ProtoUnreach,
+    HostUnreach,
+    NetUnreach,
+    AdminProhibited,
+    NoResponse,
+    /// Out of file descriptors, buffers or local ports
+    ResourceLimit,
+    LocalError,
+    /// Replies to host discovery probes
+    EchoReply,
+    TimestampReply,
+    AddressMaskReply,
+    ArpResponse,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Reason::SynAck => "syn-ack",
+            Reason::ConnRefused => "conn-refused",
+            Reason::Reset => "reset",
+            Reason::UdpResponse => "udp-response",
+            Reason::PortUnreach => "port-unreach",
+            Reason::ProtoUnreach => "proto-unreach",
+            Reason::HostUnreach => "host-unreach",
+            Reason::NetUnreach => "net-unreach",
+            Reason::AdminProhibited => "admin-prohibited",
+            Reason::NoResponse => "no-response",
+            Reason::ResourceLimit => "resource-limit",
+            Reason::LocalError => "local-error",
+            Reason::EchoReply => "echo-reply",
+            Reason::TimestampReply => "timestamp-reply",
+            Reason::AddressMaskReply => "addressmask-reply",
+            Reason::ArpResponse => "arp-response",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Result of probing a port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortStatus {
+    pub state: PortState,
+    pub reason: Reason,
+}
+
+impl PortStatus {
+    pub const fn new(state: PortState, reason: Reason) -> Self {
+        PortStatus { state, reason }
+    }
+
+    /// Status of a port whose probe failed with the error. The operating
+    /// system reports ICMP errors about connected sockets as errors of the
+    /// socket. Errors that are not about the network, like running out of
+    /// file descriptors (EMFILE), are local errors, never closed ports.
+    pub fn from_error(err: &io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(libc::ECONNREFUSED) => PortStatus::new(PortState::Closed, Reason::ConnRefused),
+            Some(libc::EHOSTUNREACH) | Some(libc::EHOSTDOWN) => PortStatus::new(PortState::Unreachable, Reason::HostUnreach),
+            Some(libc::ENETUNREACH) | Some(libc::ENONET) => PortStatus::new(PortState::Unreachable, Reason::NetUnreach),
+            // Rejected by the local packet filter
+            Some(libc::EACCES) | Some(libc::EPERM) => PortStatus::new(PortState::Filtered, Reason::AdminProhibited),
+            Some(libc::ENOPROTOOPT) => PortStatus::new(PortState::Filtered, Reason::ProtoUnreach),
+            Some(libc::ETIMEDOUT) => PortStatus::new(PortState::Filtered, Reason::NoResponse),
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) | Some(libc::EADDRNOTAVAIL) => {
+                PortStatus::new(PortState::LocalError, Reason::ResourceLimit)
+            }
+            _ => PortStatus::new(PortState::LocalError, Reason::LocalError),
+        }
+    }
+
+    /// Status of a port whose probe failed on a socket with `set_recv_err`,
+    /// from the ICMP unreachable message behind the error if there was one
+    pub fn from_socket_error(socket: &impl AsRawFd, ipv6: bool, err: &io::Error) -> Self {
+        match queued_unreachable_code(socket) {
+            Some(code) => PortStatus::from_unreachable(code, ipv6),
+            None => PortStatus::from_error(err),
+        }
+    }
+
+    /// Administratively prohibited means a firewall rejected the probe, the
+    /// other codes mean the host itself could not be reached
+    pub fn from_unreachable(code: u8, ipv6: bool) -> Self {
+        let reason = match (ipv6, code) {
+            (false, 0) | (false, 6) | (false, 11) | (true, 0) => Reason::NetUnreach,
+            (false, 2) => Reason::ProtoUnreach,
+            (false, 3) | (true, 4) => Reason::PortUnreach,
+            (false, 9) | (false, 10) | (false, 13) | (true, 1) | (true, 5) | (true, 6) => Reason::AdminProhibited,
+            _ => Reason::HostUnreach,
+        };
+        match reason {
+            Reason::NetUnreach | Reason::HostUnreach => PortStatus::new(PortState::Unreachable, reason),
+            _ => PortStatus::new(PortState::Filtered, reason),
+        }
+    }
+}
+
+impl fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.state, self.reason)
+    }
+}
+
+/// Everything learned about a host, handed to the output as soon as the
+/// host is done
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostResult {
+    pub host: HostAddr,
+    pub up: HostUp,
+    /// By protocol and ascending port, `None` without port scan
+    pub port_states: Option<BTreeMap<(Protocol, u16), PortStatus>>,
+    /// Round trip times measured by the port scan
+    pub rtt_stats: Option<RttStats>,
+    /// Start of host discovery and end of the port scan
+    pub started: SystemTime,
+    pub finished: SystemTime,
+}
+
+/// Limits every probe obeys, shared by all hosts
+pub struct ProbeLimits {
+    pub parallelism: Parallelism,
+    /// Probes per second to all hosts together
+    pub max_rate: Option<RateLimiter>,
+    /// Probes per second to each host
+    pub max_host_rate: Option<f64>,
+}
+
+impl ProbeLimits {
+    pub fn new(timing: &TimingPolicy) -> Self {
+        ProbeLimits {
+            parallelism: Parallelism::new(timing.min_parallelism, timing.max_parallelism, timing.min_rate),
+            max_rate: timing.max_rate.map(RateLimiter::new),
+            max_host_rate: timing.max_host_rate,
+        }
+    }
+
+    /// Wait until a probe to the host with the rate limiter may be sent, the
+    /// probe is in flight while the permit is held
+    pub async fn acquire(&self, host_rate: Option<&RateLimiter>) -> ProbePermit<'_> {
+        if let Some(host_rate) = host_rate {
+            host_rate.wait().await;
+        }
+        let permit = self.parallelism.acquire().await;
+        if let Some(max_rate) = &self.max_rate {
+            max_rate.wait().await;
+        }
+        permit
+    }
+
+    /// Rate limit for the probes of one host
+    pub fn host_rate_limiter(&self) -> Option<RateLimiter> {
+        self.max_host_rate.map(RateLimiter::new)
+    }
+}
+
+/// How TCP ports are probed
+#[derive(Clone)]
+pub enum TcpScan {
+    /// Complete the TCP handshake with the operating system's `connect`
+    Connect,
+    /// Send raw SYN packets and never complete the handshake
+    Syn(Arc<SynScanner>),
+}
+
+/// Probes retried after running out of local resources, before the port is
+/// reported as a local error
+const MAX_RESOURCE_RETRIES: u32 = 10;
+const RESOURCE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Scan the ports of a host. The retransmission timeout starts at the
+/// initial timeout of the timing policy, or from the round trip time
+/// measured by host discovery, and adapts to the answers of the host. The
+/// probes in flight and their rate are limited by `limits`. Returns the
+/// states with the round trip times measured on the way.
+pub async fn get_port_states(
+    host: HostAddr,
+    ports: Vec<(Protocol, u16)>,
+    timing: &TimingPolicy,
+    tcp_scan: TcpScan,
+    discovery_rtt: Option<Duration>,
+    limits: Arc<ProbeLimits>,
+) -> (BTreeMap<(Protocol, u16), PortStatus>, Option<RttStats>) {
+    let max_parallelism = limits.parallelism.max();
+    let max_retries = timing.max_retries;
+    let host_rate = Arc::new(limits.host_rate_limiter());
+    let rtt = Arc::new(RttEstimator::new(timing));
+    if let Some(discovery_rtt) = discovery_rtt {
+        rtt.update(discovery_rtt);
+    }
+    let send_delay = Arc::new(SendDelay::default());
+    let route = Arc::new(Route::new(host));
+    let port_states = stream::iter(ports).map(|(protocol, port)| {
+        let tcp_scan = tcp_scan.clone();
+        let rtt = rtt.clone();
+        let send_delay = send_delay.clone();
+        let host_rate = host_rate.clone();
+        let route = route.clone();
+        let limits = limits.clone();
+        async move {
+            let host_timing = HostTiming {
+                max_retries,
+                rtt: &rtt,
+                send_delay: &send_delay,
+                host_rate: host_rate.as_ref().as_ref(),
+                route: &route,
+                limits: &limits,
+            };
+            let port_status = probe_port(host, (protocol, port), &tcp_scan, host_timing).await;
+            ((protocol, port), port_status)
+        }
+    }).buffer_unordered(max_parallelism).collect().await;
+    (port_states, rtt.stats())
+}
+
+/// State shared by the probes of one host
+#[derive(Clone, Copy)]
+struct HostTiming<'a> {
+    max_retries: u8,
+    rtt: &'a RttEstimator,
+    send_delay: &'a SendDelay,
+    host_rate: Option<&'a RateLimiter>,
+    route: &'a Route,
+    limits: &'a ProbeLimits,
+}
+
+/// Probe a port, only probes without any answer are retransmitted. Probes
+/// that ran out of local resources are retried with fewer probes in flight.
+async fn probe_port(host: HostAddr, (protocol, port): (Protocol, u16), tcp_scan: &TcpScan, timing: HostTiming<'_>) -> PortStatus {
+    let (mut attempt, mut resource_retries) = (0, 0);
+    loop {
+        let mut timeout = timing.rtt.timeout();
+        if protocol == Protocol::Udp {
+            timing.send_delay.wait().await;
+            // Rate limited hosts need longer to answer
+            timeout += timing.send_delay.delay();
+        }
+        let permit = timing.limits.acquire(timing.host_rate).await;
+        let sent = Instant::now();
+        let port_status = match (protocol, tcp_scan) {
+            (Protocol::Udp, _) => udp_probe(host, port, timeout).await,
+            (_, TcpScan::Connect) => connect_probe(host, port, timeout).await,
+            (_, TcpScan::Syn(scanner)) => scanner.probe(timing.route, port, timeout).await,
+        };
+        drop(permit);
+
+        match port_status.reason {
+            Reason::NoResponse if attempt < timing.max_retries => {
+                attempt += 1;
+                continue;
+            }
+            Reason::ResourceLimit if resource_retries < MAX_RESOURCE_RETRIES => {
+                timing.limits.parallelism.back_off();
+                resource_retries += 1;
+                tokio::time::sleep(RESOURCE_RETRY_DELAY * resource_retries).await;
+                continue;
+            }
+            Reason::ResourceLimit | Reason::LocalError => return port_status,
+            _ => timing.limits.parallelism.record_success(),
+        }
+        let answered_by_host = matches!(port_status.state, PortState::Open | PortState::Closed);
+        if attempt == 0 && answered_by_host {
+            // Answers to retransmissions are ambiguous and not measured
+            timing.rtt.update(sent.elapsed());
+        } else if attempt > 0 && protocol == Protocol::Udp && port_status.reason != Reason::NoResponse {
+            // The answer to an earlier probe was probably suppressed by the
+            // host's ICMP rate limit
+            timing.send_delay.slow_down();
+        }
+        return port_status;
+    }
+}
+
+pub async fn connect_probe(host: HostAddr, port: u16, timeout: Duration) -> PortStatus {
+    match tokio::time::timeout(timeout, connect(host.socket_addr(port))).await {
+        Ok(port_status) => port_status,
+        Err(_) => PortStatus::new(PortState::Filtered, Reason::NoResponse),
+    }
+}
+
+/// Connect like `TcpStream::connect`, but keep the socket after a failed
+/// attempt to read the ICMP message that rejected it
+async fn connect(address: SocketAddr) -> PortStatus {
+    let socket = match connect_socket(address) {
+        Ok(socket) => socket,
+        Err(err) => return PortStatus::from_error(&err),
+    };
+    let ipv6 = address.is_ipv6();
+    match socket.connect(&address.into()) {
+        Ok(()) => return PortStatus::new(PortState::Open, Reason::SynAck),
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(err) => return PortStatus::from_socket_error(&socket, ipv6, &err),
+    }
+    let stream = match TcpStream::from_std(socket.into()) {
+        Ok(stream) => stream,
+        Err(err) => return PortStatus::from_error(&err),
+    };
+    // The socket turns writable once the handshake is done or failed
+    let result = match stream.writable().await {
+        Ok(()) => stream.take_error(),
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(None) => PortStatus::new(PortState::Open, Reason::SynAck),
+        Ok(Some(err)) | Err(err) => PortStatus::from_socket_error(&stream, ipv6, &err),
+    }
+}
+
+fn connect_socket(address: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(socket2::Protocol::TCP))?;
+    socket.set_nonblocking(true)?;
+    set_recv_err(&socket, address.is_ipv6())?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_local_errors_are_not_closed_ports() {
+        let status = |errno| PortStatus::from_error(&io::Error::from_raw_os_error(errno));
+        assert_eq!(status(libc::ECONNREFUSED), PortStatus::new(PortState::Closed, Reason::ConnRefused));
+        assert_eq!(status(libc::EHOSTUNREACH), PortStatus::new(PortState::Unreachable, Reason::HostUnreach));
+        assert_eq!(status(libc::EPERM), PortStatus::new(PortState::Filtered, Reason::AdminProhibited));
+        assert_eq!(status(libc::ENOPROTOOPT), PortStatus::new(PortState::Filtered, Reason::ProtoUnreach));
+        for errno in [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::EADDRNOTAVAIL] {
+            assert_eq!(status(errno), PortStatus::new(PortState::LocalError, Reason::ResourceLimit));
+        }
+        assert_eq!(status(libc::EINVAL), PortStatus::new(PortState::LocalError, Reason::LocalError));
+        assert_eq!(PortStatus::new(PortState::Open, Reason::SynAck).to_string(), "Open (syn-ack)");
+    }
+
+    #[tokio::test]
+    async fn test_connect_probe_localhost() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = HostAddr::from(IpAddr::from(Ipv4Addr::LOCALHOST));
+        let status = connect_probe(host, port, Duration::from_secs(1)).await;
+        assert_eq!(status, PortStatus::new(PortState::Open, Reason::SynAck));
+        drop(listener);
+        let status = connect_probe(host, port, Duration::from_secs(1)).await;
+        assert_eq!(status, PortStatus::new(PortState::Closed, Reason::ConnRefused));
+    }
+}
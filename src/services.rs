@@ -0,0 +1,139 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Bundled table of well known services, ordered by how often the port is found open.
+static SERVICES_TABLE: &str = include_str!("../data/rmap-services");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl FromStr for Protocol {
+    type Err = ();
+    fn from_str(protocol: &str) -> Result<Self, Self::Err> {
+        match protocol {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            "sctp" => Ok(Protocol::Sctp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Sctp => write!(f, "sctp"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Service {
+    pub name: &'static str,
+    pub port: u16,
+    pub protocol: Protocol,
+    /// Share of scanned hosts with the port open
+    pub frequency: f64,
+}
+
+/// Services of the bundled table, the most frequently open ports first
+pub fn services() -> &'static [Service] {
+    static SERVICES: OnceLock<Vec<Service>> = OnceLock::new();
+    SERVICES.get_or_init(|| {
+        let mut services = parse_services(SERVICES_TABLE);
+        services.sort_by(|a, b| b.frequency.total_cmp(&a.frequency));
+        services
+    })
+}
+
+/// Lines have the format `name port/protocol frequency`, `#` starts a comment.
+/// The table is bundled with rmap, so malformed lines are a bug.
+fn parse_services(table: &'static str) -> Vec<Service> {
+    table
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(service, _comment)| service))
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (port, protocol) = fields[1].split_once('/').expect("Service without protocol");
+            Service {
+                name: fields[0],
+                port: port.parse().expect("Bad service port"),
+                protocol: protocol.parse().expect("Bad service protocol"),
+                frequency: fields[2].parse().expect("Bad service frequency"),
+            }
+        })
+        .collect()
+}
+
+/// Port of a service name like `ssh`, ignoring case
+pub fn port_by_name(name: &str, protocol: Protocol) -> Option<u16> {
+    services()
+        .iter()
+        .find(|service| service.protocol == protocol && service.name.eq_ignore_ascii_case(name))
+        .map(|service| service.port)
+}
+
+pub fn service_name(port: u16, protocol: Protocol) -> Option<&'static str> {
+    services()
+        .iter()
+        .find(|service| service.protocol == protocol && service.port == port)
+        .map(|service| service.name)
+}
+
+/// Number of ports of the protocol in the bundled table, the most `top_ports` returns
+pub fn known_port_count(protocol: Protocol) -> usize {
+    services().iter().filter(|service| service.protocol == protocol).count()
+}
+
+/// The `count` most frequently open ports, at most `known_port_count`
+pub fn top_ports(count: usize, protocol: Protocol) -> Vec<u16> {
+    services()
+        .iter()
+        .filter(|service| service.protocol == protocol)
+        .map(|service| service.port)
+        .take(count)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_services_parse() {
+        assert!(services().len() > 100);
+        assert!(services().windows(2).all(|pair| pair[0].frequency >= pair[1].frequency));
+    }
+
+    #[test]
+    fn test_port_by_name() {
+        assert_eq!(port_by_name("ssh", Protocol::Tcp), Some(22));
+        assert_eq!(port_by_name("HTTPS", Protocol::Tcp), Some(443));
+        assert_eq!(port_by_name("snmp", Protocol::Udp), Some(161));
+        assert_eq!(port_by_name("snmp", Protocol::Tcp), None);
+        assert_eq!(port_by_name("no-such-service", Protocol::Tcp), None);
+    }
+
+    #[test]
+    fn test_service_name() {
+        assert_eq!(service_name(3306, Protocol::Tcp), Some("mysql"));
+        assert_eq!(service_name(53, Protocol::Udp), Some("domain"));
+        assert_eq!(service_name(1, Protocol::Tcp), None);
+    }
+
+    #[test]
+    fn test_top_ports() {
+        assert_eq!(top_ports(3, Protocol::Tcp), [80, 23, 443]);
+        assert_eq!(top_ports(100, Protocol::Tcp).len(), 100);
+        assert!(top_ports(usize::MAX, Protocol::Udp).contains(&53));
+        assert_eq!(top_ports(usize::MAX, Protocol::Udp).len(), known_port_count(Protocol::Udp));
+    }
+}
@@ -0,0 +1,128 @@
+/// Small, seedable pseudo random number generator (SplitMix64). Scan order
+/// only has to look random, it is not meant to be unpredictable.
+#[derive(Clone, Debug)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.0)
+    }
+
+    /// Uniformly distributed number in `0..bound`
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Reject the values that would make the lower numbers more likely
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < zone {
+                return value % bound;
+            }
+        }
+    }
+}
+
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+/// Fisher-Yates shuffle
+pub fn shuffle<T>(items: &mut [T], rng: &mut SplitMix64) {
+    for index in (1..items.len()).rev() {
+        let other = rng.below(index as u64 + 1) as usize;
+        items.swap(index, other);
+    }
+}
+
+const FEISTEL_ROUNDS: usize = 4;
+
+/// Pseudo random permutation of `0..len`. Every position is computed on its
+/// own with a Feistel network, so the permutation needs constant memory even
+/// for the largest address ranges.
+#[derive(Clone, Debug)]
+pub struct Permutation {
+    len: u128,
+    half_bits: u32,
+    keys: [u64; FEISTEL_ROUNDS],
+}
+
+impl Permutation {
+    pub fn new(len: u128, seed: u64) -> Self {
+        let bits = 128 - len.saturating_sub(1).leading_zeros();
+        let mut rng = SplitMix64::new(seed);
+        let mut keys = [0; FEISTEL_ROUNDS];
+        keys.iter_mut().for_each(|key| *key = rng.next_u64());
+        Permutation { len, half_bits: bits.div_ceil(2).max(1), keys }
+    }
+
+    /// Position `index` of the permutation, `index` must be less than `len`
+    pub fn get(&self, index: u128) -> u128 {
+        // The network permutes a power of two sized domain of less than 4 * len
+        // values. Walking the cycle until a value lands in range keeps it a permutation.
+        let mut value = self.feistel(index);
+        while value >= self.len {
+            value = self.feistel(value);
+        }
+        value
+    }
+
+    fn feistel(&self, value: u128) -> u128 {
+        let mask = u128::MAX >> (128 - self.half_bits);
+        let (mut left, mut right) = (value >> self.half_bits, value & mask);
+        for key in self.keys {
+            let round = u128::from(mix(right as u64 ^ key)) & mask;
+            let next_right = left ^ round;
+            left = right;
+            right = next_right;
+        }
+        left << self.half_bits | right
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permutation_visits_each_index_once() {
+        for len in [1, 2, 3, 255, 256, 1000] {
+            let permutation = Permutation::new(len, 42);
+            let mut seen = vec![false; len as usize];
+            for index in 0..len {
+                let value = permutation.get(index) as usize;
+                assert!(!seen[value]);
+                seen[value] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn test_permutation_depends_on_seed() {
+        let order = |seed| (0..100).map(|index| Permutation::new(100, seed).get(index)).collect::<Vec<_>>();
+        assert_eq!(order(1), order(1));
+        assert_ne!(order(1), order(2));
+        assert_ne!(order(1), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_permutation_of_whole_ipv6_space() {
+        let permutation = Permutation::new(u128::MAX, 7);
+        assert!(permutation.get(0) < u128::MAX);
+        assert_ne!(permutation.get(0), permutation.get(1));
+    }
+
+    #[test]
+    fn test_shuffle_keeps_items() {
+        let mut items: Vec<u16> = (1..=100).collect();
+        shuffle(&mut items, &mut SplitMix64::new(3));
+        assert_ne!(items, (1..=100).collect::<Vec<_>>());
+        items.sort_unstable();
+        assert_eq!(items, (1..=100).collect::<Vec<_>>());
+    }
+}
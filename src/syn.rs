@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol as SocketProtocol, SockAddr, Socket};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::raw::{
+    internet_checksum, ipv4_payload, is_transient, raw_socket, recv_from, send_to, Route, ICMPV6_DEST_UNREACH, ICMP_DEST_UNREACH,
+};
+use crate::scan::{PortState, PortStatus, Reason};
+use crate::shuffle::SplitMix64;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// Maximum segment size option, like the SYN of a regular connection
+const MSS_OPTION: [u8; 4] = [2, 4, 0x05, 0xb4];
+
+type Pending = Arc<Mutex<PendingProbes>>;
+
+#[derive(Default)]
+struct PendingProbes {
+    /// Probes waiting for a reply, by remote address, remote port and local port
+    probes: HashMap<(IpAddr, u16, u16), oneshot::Sender<PortStatus>>,
+    /// A listener stopped on a socket error, replies are no longer received
+    failed: bool,
+}
+
+impl PendingProbes {
+    /// Fail the waiting probes and the ones sent from now on
+    fn fail(&mut self) {
+        self.failed = true;
+        for (_, sender) in self.probes.drain() {
+            let _ = sender.send(PortStatus::new(PortState::LocalError, Reason::LocalError));
+        }
+    }
+}
+
+/// Half-open TCP scanner. SYN packets are sent from a raw socket and a
+/// listener task per address family matches the replies to the waiting probes:
+/// SYN/ACK means open, RST means closed. The operating system answers the
+/// SYN/ACK with a RST as it does not know the connection, so no connection
+/// is ever established. ICMP destination unreachable messages about a SYN
+/// probe are matched as well, they tell filtered from unreachable ports.
+pub struct SynScanner {
+    /// Only the address families of the targets have sockets
+    ipv4: Option<Arc<AsyncFd<Socket>>>,
+    ipv6: Option<Arc<AsyncFd<Socket>>>,
+    /// Source port of the SYN probes, ACK probes use the next port.
+    /// Replies to other ports are ignored.
+    source_port: u16,
+    /// Key for the initial sequence numbers, so replies can be verified
+    secret: u64,
+    pending: Pending,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl SynScanner {
+    /// Open the raw sockets of the address families to scan, fails without
+    /// CAP_NET_RAW. Must be called from within the tokio runtime.
+    pub fn new(seed: u64, ipv4: bool, ipv6: bool) -> io::Result<Self> {
+        let tcpv4 = family_socket(ipv4, Domain::IPV4, SocketProtocol::TCP, "IPv4 TCP")?;
+        let tcpv6 = family_socket(ipv6, Domain::IPV6, SocketProtocol::TCP, "IPv6 TCP")?;
+        let icmpv4 = family_socket(ipv4, Domain::IPV4, SocketProtocol::ICMPV4, "ICMP")?;
+        let icmpv6 = family_socket(ipv6, Domain::IPV6, SocketProtocol::ICMPV6, "ICMPv6")?;
+        let mut rng = SplitMix64::new(seed);
+        let source_port = 40000 + rng.below(20000) as u16;
+        let secret = rng.next_u64();
+        let pending = Pending::default();
+
+        let mut listeners = vec![];
+        if let Some(socket) = &tcpv4 {
+            listeners.push(tokio::spawn(listen(socket.clone(), false, source_port, secret, pending.clone())));
+        }
+        if let Some(socket) = &tcpv6 {
+            listeners.push(tokio::spawn(listen(socket.clone(), true, source_port, secret, pending.clone())));
+        }
+        if let Some(socket) = icmpv4 {
+            listeners.push(tokio::spawn(listen_unreachable(socket, false, source_port, secret, pending.clone())));
+        }
+        if let Some(socket) = icmpv6 {
+            listeners.push(tokio::spawn(listen_unreachable(socket, true, source_port, secret, pending.clone())));
+        }
+        Ok(SynScanner { ipv4: tcpv4, ipv6: tcpv6, source_port, secret, pending, listeners })
+    }
+
+    pub async fn probe(&self, route: &Route, port: u16, timeout: Duration) -> PortStatus {
+        self.probe_with(route, port, timeout, TCP_SYN).await
+    }
+
+    /// Send a TCP ACK, hosts answer it with RST whether the port is open or
+    /// closed, so the port state is closed for any answer. Firewalls that only
+    /// block new connections let the ACK pass.
+    pub async fn ack_probe(&self, route: &Route, port: u16, timeout: Duration) -> PortStatus {
+        self.probe_with(route, port, timeout, TCP_ACK).await
+    }
+
+    async fn probe_with(&self, route: &Route, port: u16, timeout: Duration, flags: u8) -> PortStatus {
+        let host = route.host;
+        let source_port = if flags == TCP_SYN { self.source_port } else { self.source_port + 1 };
+        let (sender, reply) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.failed {
+                return PortStatus::new(PortState::LocalError, Reason::LocalError);
+            }
+            pending.probes.insert((host.ip, port, source_port), sender);
+        }
+
+        let port_status = match self.send(route, port, source_port, flags).await {
+            Ok(()) => match tokio::time::timeout(timeout, reply).await {
+                Ok(Ok(port_status)) => port_status,
+                _ => PortStatus::new(PortState::Filtered, Reason::NoResponse),
+            },
+            // Nothing left the host, so the port was not probed at all
+            Err(err) => PortStatus::from_error(&err),
+        };
+        self.pending.lock().unwrap().probes.remove(&(host.ip, port, source_port));
+        port_status
+    }
+
+    async fn send(&self, route: &Route, port: u16, source_port: u16, flags: u8) -> io::Result<()> {
+        let (host, source) = (route.host, route.source()?);
+        let sequence = initial_sequence(self.secret, host.ip, port);
+        let packet = tcp_packet(source, host.ip, (source_port, port), sequence, flags);
+        let socket = if host.ip.is_ipv6() { &self.ipv6 } else { &self.ipv4 };
+        let socket = socket.as_ref().ok_or_else(|| io::Error::from_raw_os_error(libc::EAFNOSUPPORT))?;
+        send_to(socket, &packet, &SockAddr::from(host.socket_addr(0))).await
+    }
+}
+
+impl Drop for SynScanner {
+    fn drop(&mut self) {
+        self.listeners.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Raw socket of an address family if it is scanned, the error names the socket
+fn family_socket(scanned: bool, domain: Domain, protocol: SocketProtocol, name: &str) -> io::Result<Option<Arc<AsyncFd<Socket>>>> {
+    if !scanned {
+        return Ok(None);
+    }
+    match raw_socket(domain, protocol) {
+        Ok(socket) => Ok(Some(Arc::new(socket))),
+        Err(err) => Err(io::Error::new(err.kind(), format!("cannot open a raw {name} socket: {err}"))),
+    }
+}
+
+fn initial_sequence(secret: u64, ip: IpAddr, port: u16) -> u32 {
+    let ip = match ip {
+        IpAddr::V4(ip) => u128::from(u32::from(ip)),
+        IpAddr::V6(ip) => u128::from(ip),
+    };
+    let seed = secret ^ (ip as u64) ^ ((ip >> 64) as u64).rotate_left(17) ^ u64::from(port) << 48;
+    SplitMix64::new(seed).next_u64() as u32
+}
+
+/// TCP segment with the given flags. SYN segments carry the sequence number,
+/// ACK segments acknowledge it, so the RST reply carries it as its sequence number.
+fn tcp_packet(source: IpAddr, destination: IpAddr, (source_port, port): (u16, u16), sequence: u32, flags: u8) -> Vec<u8> {
+    let options: &[u8] = if flags == TCP_SYN { &MSS_OPTION } else { &[] };
+    let mut packet = vec![0; 20 + options.len()];
+    packet[0..2].copy_from_slice(&source_port.to_be_bytes());
+    packet[2..4].copy_from_slice(&port.to_be_bytes());
+    if flags == TCP_SYN {
+        packet[4..8].copy_from_slice(&sequence.to_be_bytes());
+    } else {
+        packet[8..12].copy_from_slice(&sequence.to_be_bytes());
+    }
+    packet[12] = (packet.len() as u8 / 4) << 4;
+    packet[13] = flags;
+    packet[14..16].copy_from_slice(&1024_u16.to_be_bytes());
+    packet[20..].copy_from_slice(options);
+
+    let checksum = tcp_checksum(source, destination, &packet);
+    packet[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// Internet checksum of a TCP segment including the pseudo header
+fn tcp_checksum(source: IpAddr, destination: IpAddr, segment: &[u8]) -> u16 {
+    let mut pseudo_header = vec![];
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&[0, 6]);
+            pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        _ => {
+            pseudo_header.extend_from_slice(&ipv6_octets(source));
+            pseudo_header.extend_from_slice(&ipv6_octets(destination));
+            pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, 6]);
+        }
+    }
+    internet_checksum(pseudo_header.iter().chain(segment))
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TcpReply {
+    remote: IpAddr,
+    remote_port: u16,
+    local_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+}
+
+fn parse_reply(packet: &[u8], from: IpAddr, ipv6: bool) -> Option<TcpReply> {
+    let (remote, segment) = if ipv6 { (from, packet) } else { ipv4_payload(packet)? };
+    if segment.len() < 20 {
+        return None;
+    }
+
+    let word = |offset: usize| u32::from_be_bytes([segment[offset], segment[offset + 1], segment[offset + 2], segment[offset + 3]]);
+    Some(TcpReply {
+        remote,
+        remote_port: u16::from_be_bytes([segment[0], segment[1]]),
+        local_port: u16::from_be_bytes([segment[2], segment[3]]),
+        sequence: word(4),
+        acknowledgment: word(8),
+        flags: segment[13],
+    })
+}
+
+fn classify_reply(reply: &TcpReply) -> Option<PortStatus> {
+    if reply.flags & TCP_RST != 0 {
+        Some(PortStatus::new(PortState::Closed, Reason::Reset))
+    } else if reply.flags & (TCP_SYN | TCP_ACK | TCP_FIN) == TCP_SYN | TCP_ACK {
+        Some(PortStatus::new(PortState::Open, Reason::SynAck))
+    } else {
+        None
+    }
+}
+
+/// Replies to SYN probes acknowledge the initial sequence number, RST replies
+/// to ACK probes carry it as their sequence number
+fn is_reply_to_probe(reply: &TcpReply, initial_sequence: u32) -> bool {
+    reply.acknowledgment == initial_sequence.wrapping_add(1) || (reply.flags & TCP_RST != 0 && reply.sequence == initial_sequence)
+}
+
+async fn listen(socket: Arc<AsyncFd<Socket>>, ipv6: bool, source_port: u16, secret: u64, pending: Pending) {
+    let mut buffer = vec![MaybeUninit::<u8>::uninit(); 65535];
+    loop {
+        let (packet, from) = match recv_from(&socket, &mut buffer).await {
+            Ok(received) => received,
+            Err(err) if is_transient(&err) => continue,
+            Err(_) => return pending.lock().unwrap().fail(),
+        };
+        let from = match from.as_socket() {
+            Some(from) => from.ip(),
+            None => continue,
+        };
+
+        let reply = match parse_reply(packet, from, ipv6) {
+            Some(reply) if reply.local_port == source_port || reply.local_port == source_port + 1 => reply,
+            _ => continue,
+        };
+        let sequence = initial_sequence(secret, reply.remote, reply.remote_port);
+        if let (Some(port_status), true) = (classify_reply(&reply), is_reply_to_probe(&reply, sequence)) {
+            if let Some(sender) = pending.lock().unwrap().probes.remove(&(reply.remote, reply.remote_port, reply.local_port)) {
+                let _ = sender.send(port_status);
+            }
+        }
+    }
+}
+
+/// ICMP destination unreachable message about a probe. It quotes the IP
+/// header and at least the first eight bytes of the probe's TCP header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Unreachable {
+    remote: IpAddr,
+    remote_port: u16,
+    local_port: u16,
+    sequence: u32,
+    port_status: PortStatus,
+}
+
+fn parse_unreachable(packet: &[u8], ipv6: bool) -> Option<Unreachable> {
+    let message = if ipv6 { packet } else { ipv4_payload(packet)?.1 };
+    let (message_type, code) = (*message.first()?, *message.get(1)?);
+    let quoted = message.get(8..)?;
+    let (remote, segment) = match ipv6 {
+        false if message_type == ICMP_DEST_UNREACH && quoted.first()? >> 4 == 4 && *quoted.get(9)? == 6 => {
+            let header_len = usize::from(quoted[0] & 0x0f) * 4;
+            let remote: [u8; 4] = quoted.get(16..20)?.try_into().ok()?;
+            (IpAddr::from(remote), quoted.get(header_len..)?)
+        }
+        // Extension headers are never added to the probes
+        true if message_type == ICMPV6_DEST_UNREACH && quoted.first()? >> 4 == 6 && *quoted.get(6)? == 6 => {
+            let remote: [u8; 16] = quoted.get(24..40)?.try_into().ok()?;
+            (IpAddr::from(remote), quoted.get(40..)?)
+        }
+        _ => return None,
+    };
+    if segment.len() < 8 {
+        return None;
+    }
+    Some(Unreachable {
+        remote,
+        remote_port: u16::from_be_bytes([segment[2], segment[3]]),
+        local_port: u16::from_be_bytes([segment[0], segment[1]]),
+        sequence: u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]),
+        port_status: PortStatus::from_unreachable(code, ipv6),
+    })
+}
+
+/// Only SYN probes are matched, ACK probes carry no sequence number to verify
+async fn listen_unreachable(socket: Arc<AsyncFd<Socket>>, ipv6: bool, source_port: u16, secret: u64, pending: Pending) {
+    let mut buffer = vec![MaybeUninit::<u8>::uninit(); 65535];
+    loop {
+        let packet = match recv_from(&socket, &mut buffer).await {
+            Ok((packet, _)) => packet,
+            Err(err) if is_transient(&err) => continue,
+            Err(_) => return pending.lock().unwrap().fail(),
+        };
+        let unreachable = match parse_unreachable(packet, ipv6) {
+            Some(unreachable) if unreachable.local_port == source_port => unreachable,
+            _ => continue,
+        };
+        if unreachable.sequence == initial_sequence(secret, unreachable.remote, unreachable.remote_port) {
+            let key = (unreachable.remote, unreachable.remote_port, unreachable.local_port);
+            if let Some(sender) = pending.lock().unwrap().probes.remove(&key) {
+                let _ = sender.send(unreachable.port_status);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_tcp_packet_checksum_verifies() {
+        let source = IpAddr::from(Ipv4Addr::new(192, 168, 1, 10));
+        let destination = IpAddr::from(Ipv4Addr::new(192, 168, 1, 1));
+        let packet = tcp_packet(source, destination, (45000, 22), 0x1234_5678, TCP_SYN);
+        assert_eq!(packet.len(), 24);
+        assert_eq!(packet[13], TCP_SYN);
+        assert_eq!(tcp_checksum(source, destination, &packet), 0);
+
+        let source = IpAddr::from(Ipv6Addr::LOCALHOST);
+        let packet = tcp_packet(source, source, (45000, 22), 1, TCP_ACK);
+        assert_eq!(packet.len(), 20);
+        assert_eq!(packet[8..12], [0, 0, 0, 1]);
+        assert_eq!(tcp_checksum(source, source, &packet), 0);
+    }
+
+    #[test]
+    fn test_parse_and_classify_ipv4_reply() {
+        let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        packet.extend_from_slice(&[0, 22, 0xaf, 0xc8, 0, 0, 0, 9, 0x12, 0x34, 0x56, 0x79, 0x50, TCP_SYN | TCP_ACK]);
+        packet.extend_from_slice(&[0; 6]);
+
+        let reply = parse_reply(&packet, Ipv4Addr::UNSPECIFIED.into(), false).unwrap();
+        assert_eq!(reply, TcpReply {
+            remote: Ipv4Addr::new(10, 0, 0, 1).into(),
+            remote_port: 22,
+            local_port: 45000,
+            sequence: 9,
+            acknowledgment: 0x1234_5679,
+            flags: TCP_SYN | TCP_ACK,
+        });
+        assert_eq!(classify_reply(&reply), Some(PortStatus::new(PortState::Open, Reason::SynAck)));
+        let rst = TcpReply { flags: TCP_RST | TCP_ACK, ..reply };
+        assert_eq!(classify_reply(&rst), Some(PortStatus::new(PortState::Closed, Reason::Reset)));
+        assert!(parse_reply(&packet[..30], Ipv4Addr::UNSPECIFIED.into(), false).is_none());
+    }
+
+    #[test]
+    fn test_ignore_syn_without_ack() {
+        let reply = TcpReply {
+            remote: Ipv4Addr::LOCALHOST.into(),
+            remote_port: 1,
+            local_port: 2,
+            sequence: 0,
+            acknowledgment: 0,
+            flags: TCP_SYN,
+        };
+        assert_eq!(classify_reply(&reply), None);
+    }
+
+    #[test]
+    fn test_reply_matches_probe() {
+        let syn_ack = TcpReply {
+            remote: Ipv4Addr::LOCALHOST.into(),
+            remote_port: 80,
+            local_port: 45000,
+            sequence: 7,
+            acknowledgment: 101,
+            flags: TCP_SYN | TCP_ACK,
+        };
+        assert!(is_reply_to_probe(&syn_ack, 100));
+        assert!(!is_reply_to_probe(&syn_ack, 7));
+
+        let rst = TcpReply { sequence: 100, acknowledgment: 0, flags: TCP_RST, ..syn_ack };
+        assert!(is_reply_to_probe(&rst, 100));
+        assert!(!is_reply_to_probe(&rst, 101));
+    }
+
+    #[tokio::test]
+    async fn test_failed_listener_fails_probes() {
+        let pending = Pending::default();
+        let (sender, reply) = oneshot::channel();
+        pending.lock().unwrap().probes.insert((Ipv4Addr::LOCALHOST.into(), 22, 45000), sender);
+        pending.lock().unwrap().fail();
+        assert_eq!(reply.await, Ok(PortStatus::new(PortState::LocalError, Reason::LocalError)));
+        assert!(pending.lock().unwrap().failed);
+        assert!(pending.lock().unwrap().probes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_unreachable_about_probe() {
+        let source = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        let destination = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        let mut packet = vec![0x45, 0, 0, 76, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 254, 10, 0, 0, 2];
+        packet.extend_from_slice(&[ICMP_DEST_UNREACH, 1, 0, 0, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[0x45, 0, 0, 44, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1]);
+        packet.extend_from_slice(&tcp_packet(source, destination, (45000, 22), 0x1234_5678, TCP_SYN));
+
+        let unreachable = parse_unreachable(&packet, false).unwrap();
+        assert_eq!(unreachable, Unreachable {
+            remote: destination,
+            remote_port: 22,
+            local_port: 45000,
+            sequence: 0x1234_5678,
+            port_status: PortStatus::new(PortState::Unreachable, Reason::HostUnreach),
+        });
+        packet[21] = 13;
+        let port_status = parse_unreachable(&packet, false).unwrap().port_status;
+        assert_eq!(port_status, PortStatus::new(PortState::Filtered, Reason::AdminProhibited));
+        // Echo replies and messages quoting too little are ignored
+        assert!(parse_unreachable(&packet[..50], false).is_none());
+        packet[20] = 0;
+        assert!(parse_unreachable(&packet, false).is_none());
+
+        let source = IpAddr::from(Ipv6Addr::LOCALHOST);
+        let mut packet = vec![ICMPV6_DEST_UNREACH, 1, 0, 0, 0, 0, 0, 0, 0x60, 0, 0, 0, 0, 24, 6, 64];
+        packet.extend_from_slice(&ipv6_octets(source));
+        packet.extend_from_slice(&ipv6_octets(source));
+        packet.extend_from_slice(&tcp_packet(source, source, (45000, 443), 7, TCP_SYN));
+        let unreachable = parse_unreachable(&packet, true).unwrap();
+        assert_eq!((unreachable.remote, unreachable.remote_port, unreachable.sequence), (source, 443, 7));
+        assert_eq!(unreachable.port_status, PortStatus::new(PortState::Filtered, Reason::AdminProhibited));
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::discovery::HostUp;
+use crate::hosts::{HostAddr, HostName};
+use crate::output::{Output, RunStats};
+use crate::scan::{HostResult, PortState, PortStatus};
+use crate::services::{service_name, Protocol};
+
+/// The human readable output: a line per host with the number of ports by
+/// state, and the ports themselves if shown
+pub struct TextWriter<W: Write> {
+    writer: W,
+    show_ports: bool,
+}
+
+impl<W: Write> TextWriter<W> {
+    pub fn new(writer: W, show_ports: bool) -> Self {
+        TextWriter { writer, show_ports }
+    }
+
+    fn write_host_up(&mut self, up: &HostUp) -> io::Result<()> {
+        match (up.mac, up.mac.and_then(|mac| mac.vendor())) {
+            (Some(mac), Some(vendor)) => writeln!(self.writer, "    MAC address: {mac} ({vendor})"),
+            (Some(mac), None) => writeln!(self.writer, "    MAC address: {mac}"),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Output for TextWriter<W> {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()> {
+        let label = host_label(&result.host, name);
+        let port_states = match &result.port_states {
+            Some(port_states) => port_states,
+            None => {
+                match result.up.rtt {
+                    Some(rtt) => writeln!(self.writer, "{} is up (rtt: {})", label, format_rtt(rtt))?,
+                    None => writeln!(self.writer, "{} is up", label)?,
+                }
+                return self.write_host_up(&result.up);
+            }
+        };
+        let statistics = PortStatistics::from(port_states);
+        match result.up.rtt {
+            Some(rtt) => writeln!(self.writer, "{} ({}, rtt: {})", label, statistics, format_rtt(rtt))?,
+            None => writeln!(self.writer, "{} ({})", label, statistics)?,
+        }
+        self.write_host_up(&result.up)?;
+        if self.show_ports {
+            for ((protocol, port), port_status) in port_states {
+                match service_name(*port, *protocol) {
+                    Some(service) => writeln!(self.writer, "    {}/{} ({}) : {}", port, protocol, service, port_status)?,
+                    None => writeln!(self.writer, "    {}/{} : {}", port, protocol, port_status)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, _stats: &RunStats) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn host_label(host: &HostAddr, name: Option<&HostName>) -> String {
+    match name {
+        Some(HostName::Target(name)) => format!("{name} ({host})"),
+        Some(HostName::Reverse(name)) => format!("{host} [{name}]"),
+        None => host.to_string(),
+    }
+}
+
+fn format_rtt(rtt: Duration) -> String {
+    format!("{:.2} ms", rtt.as_secs_f64() * 1000.0)
+}
+
+/// Number of ports by state, unreachable ports and local errors are only
+/// shown if there are any
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PortStatistics {
+    open: usize,
+    closed: usize,
+    filtered: usize,
+    unreachable: usize,
+    local_errors: usize,
+}
+
+impl From<&BTreeMap<(Protocol, u16), PortStatus>> for PortStatistics {
+    fn from(port_states: &BTreeMap<(Protocol, u16), PortStatus>) -> Self {
+        port_states.values().fold(PortStatistics::default(), |mut statistics, port_status| {
+            match port_status.state {
+                PortState::Open => statistics.open += 1,
+                PortState::Closed => statistics.closed += 1,
+                PortState::Filtered | PortState::OpenFiltered => statistics.filtered += 1,
+                PortState::Unreachable => statistics.unreachable += 1,
+                PortState::LocalError => statistics.local_errors += 1,
+            }
+            statistics
+        })
+    }
+}
+
+impl fmt::Display for PortStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "open: {}, closed: {}, filtered: {}", self.open, self.closed, self.filtered)?;
+        if self.unreachable > 0 {
+            write!(f, ", unreachable: {}", self.unreachable)?;
+        }
+        if self.local_errors > 0 {
+            write!(f, ", local errors: {}", self.local_errors)?;
+        }
+        Ok(())
+    }
+}
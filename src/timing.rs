@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+/// nmap's timing templates, `-T0` to `-T5` or by name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TimingTemplate {
+    /// One probe every five minutes, to evade intrusion detection
+    #[value(name = "0", alias = "paranoid")]
+    Paranoid,
+    /// One probe every 15 seconds
+    #[value(name = "1", alias = "sneaky")]
+    Sneaky,
+    /// One probe every 400 ms, to spare the network and the hosts
+    #[value(name = "2", alias = "polite")]
+    Polite,
+    /// The defaults
+    #[value(name = "3", alias = "normal")]
+    Normal,
+    /// Shorter timeouts for fast and reliable networks
+    #[value(name = "4", alias = "aggressive")]
+    Aggressive,
+    /// Short timeouts and few retransmissions, trades accuracy for speed
+    #[value(name = "5", alias = "insane")]
+    Insane,
+}
+
+/// Timeouts, retransmissions, parallelism and rates of a scan
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingPolicy {
+    /// Timeout of the probes to a host until round trip times were measured
+    pub initial_rtt_timeout: Duration,
+    /// Bounds of the timeout adapted to the measured round trip times
+    pub min_rtt_timeout: Duration,
+    pub max_rtt_timeout: Duration,
+    /// Retransmissions of a probe without answer
+    pub max_retries: u8,
+    /// Probes in flight across all hosts, and the number kept when running
+    /// out of local resources
+    pub max_parallelism: usize,
+    pub min_parallelism: usize,
+    /// Hosts whose ports are scanned at the same time
+    pub max_hostgroup: usize,
+    /// Probes per second to all hosts together
+    pub max_rate: Option<f64>,
+    pub min_rate: Option<f64>,
+    /// Probes per second to each host
+    pub max_host_rate: Option<f64>,
+}
+
+impl Default for TimingPolicy {
+    fn default() -> Self {
+        TimingPolicy {
+            initial_rtt_timeout: Duration::from_secs(1),
+            min_rtt_timeout: Duration::from_millis(100),
+            max_rtt_timeout: Duration::from_secs(10),
+            max_retries: 2,
+            max_parallelism: 400,
+            min_parallelism: 1,
+            max_hostgroup: 64,
+            max_rate: None,
+            min_rate: None,
+            max_host_rate: None,
+        }
+    }
+}
+
+impl From<TimingTemplate> for TimingPolicy {
+    /// Like nmap, the slow templates wait between the probes to a host and
+    /// send one probe at a time, the fast templates shorten the timeouts. The
+    /// wait is only a host rate, replies are awaited with the usual timeout.
+    fn from(template: TimingTemplate) -> Self {
+        let serial = |scan_delay: Duration| TimingPolicy {
+            max_parallelism: 1,
+            max_hostgroup: 1,
+            max_host_rate: Some(1.0 / scan_delay.as_secs_f64()),
+            ..TimingPolicy::default()
+        };
+        match template {
+            TimingTemplate::Paranoid => serial(Duration::from_secs(300)),
+            TimingTemplate::Sneaky => serial(Duration::from_secs(15)),
+            TimingTemplate::Polite => serial(Duration::from_millis(400)),
+            TimingTemplate::Normal => TimingPolicy::default(),
+            TimingTemplate::Aggressive => TimingPolicy {
+                initial_rtt_timeout: Duration::from_millis(500),
+                max_rtt_timeout: Duration::from_millis(1250),
+                ..TimingPolicy::default()
+            },
+            TimingTemplate::Insane => TimingPolicy {
+                initial_rtt_timeout: Duration::from_millis(250),
+                min_rtt_timeout: Duration::from_millis(50),
+                max_rtt_timeout: Duration::from_millis(300),
+                max_retries: 1,
+                ..TimingPolicy::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates_get_faster() {
+        let templates = TimingTemplate::value_variants();
+        let policies: Vec<TimingPolicy> = templates.iter().map(|template| TimingPolicy::from(*template)).collect();
+        assert_eq!(TimingPolicy::from(TimingTemplate::Normal), TimingPolicy::default());
+        assert_eq!(policies[0].max_host_rate, Some(1.0 / 300.0));
+        assert_eq!(policies[0].initial_rtt_timeout, TimingPolicy::default().initial_rtt_timeout);
+        assert_eq!(policies[2].max_host_rate, Some(2.5));
+        for pair in policies.windows(2) {
+            assert!(pair[0].initial_rtt_timeout >= pair[1].initial_rtt_timeout);
+            assert!(pair[0].max_parallelism <= pair[1].max_parallelism);
+            assert!(pair[0].max_retries >= pair[1].max_retries);
+        }
+    }
+
+    #[test]
+    fn test_parse_template_by_number_or_name() {
+        assert_eq!(TimingTemplate::from_str("4", false), Ok(TimingTemplate::Aggressive));
+        assert_eq!(TimingTemplate::from_str("polite", false), Ok(TimingTemplate::Polite));
+        assert!(TimingTemplate::from_str("6", false).is_err());
+    }
+}
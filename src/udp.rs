@@ -0,0 +1,194 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::hosts::HostAddr;
+use crate::raw::set_recv_err;
+use crate::scan::{PortState, PortStatus, Reason};
+
+/// Bounds of the delay between two probes to the same host
+const MIN_SEND_DELAY: Duration = Duration::from_millis(10);
+const MAX_SEND_DELAY: Duration = Duration::from_secs(1);
+
+/// DNS query for the NS records of the root zone
+const DNS_QUERY: [u8; 17] = [
+    0x13, 0x37, // id
+    0x01, 0x00, // standard query, recursion desired
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // one question
+    0x00, // root name
+    0x00, 0x02, // NS
+    0x00, 0x01, // IN
+];
+
+/// SNMPv1 get-request of sysDescr.0 with community `public`
+const SNMP_GET: [u8; 43] = [
+    0x30, 0x29, // message
+    0x02, 0x01, 0x00, // version 1
+    0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', // community
+    0xa0, 0x1c, // get-request
+    0x02, 0x04, 0x13, 0x37, 0x13, 0x37, // request id
+    0x02, 0x01, 0x00, // error status
+    0x02, 0x01, 0x00, // error index
+    0x30, 0x0e, 0x30, 0x0c, // variable bindings
+    0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00, // 1.3.6.1.2.1.1.1.0
+    0x05, 0x00, // null value
+];
+
+/// NTP version 4 client request, any server answers it with its version
+const NTP_REQUEST: [u8; 48] = {
+    let mut request = [0; 48];
+    // No leap second information, version 4, client mode
+    request[0] = 0xe3;
+    request
+};
+
+/// Payload drawing a reply from the service commonly found on the port.
+/// Services without a payload get an empty datagram.
+pub fn udp_payload(port: u16) -> &'static [u8] {
+    match port {
+        53 => &DNS_QUERY,
+        123 => &NTP_REQUEST,
+        161 => &SNMP_GET,
+        _ => &[],
+    }
+}
+
+/// Spacing of the UDP probes to one host. Hosts limit their ICMP port
+/// unreachable messages, Linux to about one per second, so probes of closed
+/// ports get no answer and look open|filtered. When a port only answers a
+/// retransmission, the earlier reply was probably suppressed and the probes
+/// are spaced further apart, like nmap does.
+pub struct SendDelay {
+    state: Mutex<SendDelayState>,
+}
+
+struct SendDelayState {
+    delay: Duration,
+    next_send: Instant,
+}
+
+impl Default for SendDelay {
+    fn default() -> Self {
+        SendDelay { state: Mutex::new(SendDelayState { delay: Duration::ZERO, next_send: Instant::now() }) }
+    }
+}
+
+impl SendDelay {
+    /// Wait for the turn of the next probe
+    pub async fn wait(&self) {
+        let send_at = {
+            let mut state = self.state.lock().unwrap();
+            let send_at = state.next_send.max(Instant::now());
+            state.next_send = send_at + state.delay;
+            send_at
+        };
+        tokio::time::sleep_until(send_at).await;
+    }
+
+    pub fn slow_down(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.delay = (state.delay * 2).clamp(MIN_SEND_DELAY, MAX_SEND_DELAY);
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.state.lock().unwrap().delay
+    }
+}
+
+/// Probe a UDP port from a connected socket, the operating system reports
+/// ICMP port unreachable messages as refused connection. Without an answer
+/// the port is open or the probe was dropped.
+pub async fn udp_probe(host: HostAddr, port: u16, timeout: Duration) -> PortStatus {
+    let socket = match udp_socket(host, port).await {
+        Ok(socket) => socket,
+        Err(err) => return PortStatus::from_error(&err),
+    };
+    if let Err(err) = socket.send(udp_payload(port)).await {
+        return udp_error_status(&socket, &err);
+    }
+    let mut buffer = [0; 512];
+    match tokio::time::timeout(timeout, socket.recv(&mut buffer)).await {
+        Ok(Ok(_)) => PortStatus::new(PortState::Open, Reason::UdpResponse),
+        Ok(Err(err)) => udp_error_status(&socket, &err),
+        Err(_) => PortStatus::new(PortState::OpenFiltered, Reason::NoResponse),
+    }
+}
+
+/// ICMP port unreachable, or a refused UDP "connection", means the port is closed
+fn udp_error_status(socket: &UdpSocket, err: &io::Error) -> PortStatus {
+    let ipv6 = socket.local_addr().is_ok_and(|local| local.is_ipv6());
+    match PortStatus::from_socket_error(socket, ipv6, err) {
+        PortStatus { state: PortState::Closed, .. } | PortStatus { reason: Reason::PortUnreach, .. } => {
+            PortStatus::new(PortState::Closed, Reason::PortUnreach)
+        }
+        port_status => port_status,
+    }
+}
+
+async fn udp_socket(host: HostAddr, port: u16) -> io::Result<UdpSocket> {
+    let unspecified = match host.ip {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let socket = UdpSocket::bind(unspecified).await?;
+    set_recv_err(&socket, host.ip.is_ipv6())?;
+    socket.connect(host.socket_addr(port)).await?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length of a BER encoded value starting at `offset` including its header
+    fn ber_len(bytes: &[u8], offset: usize) -> usize {
+        2 + usize::from(bytes[offset + 1])
+    }
+
+    #[test]
+    fn test_snmp_get_lengths_are_consistent() {
+        assert_eq!(ber_len(&SNMP_GET, 0), SNMP_GET.len());
+        // The get-request PDU follows version and community
+        assert_eq!(13 + ber_len(&SNMP_GET, 13), SNMP_GET.len());
+        assert_eq!(27 + ber_len(&SNMP_GET, 27), SNMP_GET.len());
+    }
+
+    #[test]
+    fn test_udp_payloads() {
+        assert_eq!(udp_payload(53).len(), 17);
+        assert_eq!(udp_payload(123)[0] >> 3 & 0x07, 4);
+        assert!(udp_payload(514).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_udp_probe_localhost() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let responder = std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (len, from) = server.recv_from(&mut buffer).unwrap();
+            server.send_to(&buffer[..len], from).unwrap();
+            port
+        });
+
+        let host = HostAddr::from(IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_eq!(udp_probe(host, port, Duration::from_secs(1)).await, PortStatus::new(PortState::Open, Reason::UdpResponse));
+        let closed_port = responder.join().unwrap();
+        assert_eq!(udp_probe(host, closed_port, Duration::from_secs(1)).await, PortStatus::new(PortState::Closed, Reason::PortUnreach));
+    }
+
+    #[tokio::test]
+    async fn test_send_delay_doubles_up_to_limit() {
+        let send_delay = SendDelay::default();
+        send_delay.slow_down();
+        assert_eq!(send_delay.delay(), MIN_SEND_DELAY);
+        send_delay.slow_down();
+        assert_eq!(send_delay.delay(), MIN_SEND_DELAY * 2);
+        (0..20).for_each(|_| send_delay.slow_down());
+        assert_eq!(send_delay.delay(), MAX_SEND_DELAY);
+    }
+}
@@ -0,0 +1,223 @@
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+use crate::hosts::HostName;
+use crate::output::{port_ranges, time_string, unix_seconds, Output, RunStats, ScanInfo};
+use crate::scan::{HostResult, PortState};
+use crate::services::service_name;
+
+/// Version of nmap's XML output format that is written
+const XML_OUTPUT_VERSION: &str = "1.05";
+
+/// Writes nmap's XML output, valid against nmap's DTD, while the hosts
+/// finish. nmap's DTD only allows `nmap` as scanner, rmap names itself in a
+/// comment like nmap does.
+pub struct XmlWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> XmlWriter<W> {
+    pub fn new(mut writer: W, scan: &ScanInfo) -> io::Result<Self> {
+        let args = scan.arguments.join(" ");
+        let start = unix_seconds(scan.start_time);
+        let start_str = time_string(scan.start_time);
+        writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(writer, "<!DOCTYPE nmaprun>")?;
+        // Comments must not contain "--", the arguments may
+        let mut comment_args = args.clone();
+        while comment_args.contains("--") {
+            comment_args = comment_args.replace("--", "- -");
+        }
+        writeln!(writer, "<!-- rmap {} scan initiated {start_str} as: {comment_args} -->", env!("CARGO_PKG_VERSION"))?;
+        writeln!(
+            writer,
+            "<nmaprun scanner=\"nmap\" args=\"{}\" start=\"{start}\" startstr=\"{start_str}\" version=\"{}\" xmloutputversion=\"{XML_OUTPUT_VERSION}\">",
+            escape(&args),
+            env!("CARGO_PKG_VERSION"),
+        )?;
+        let port_ranges = port_ranges(&scan.ports);
+        for scan_type in &scan.scan_types {
+            let protocol = match *scan_type {
+                "syn" | "connect" => "tcp",
+                "udp" => "udp",
+                _ => continue,
+            };
+            let services = port_ranges.iter().find(|(port_protocol, _)| port_protocol.to_string() == protocol);
+            let num_services = scan.ports.iter().filter(|(port_protocol, _)| port_protocol.to_string() == protocol).count();
+            writeln!(
+                writer,
+                "<scaninfo type=\"{scan_type}\" protocol=\"{protocol}\" numservices=\"{num_services}\" services=\"{}\"/>",
+                services.map_or("", |(_, services)| services.as_str()),
+            )?;
+        }
+        writeln!(writer, "<verbose level=\"0\"/>")?;
+        writeln!(writer, "<debugging level=\"0\"/>")?;
+        Ok(XmlWriter { writer })
+    }
+}
+
+impl<W: Write> Output for XmlWriter<W> {
+    fn write_host(&mut self, result: &HostResult, name: Option<&HostName>) -> io::Result<()> {
+        let writer = &mut self.writer;
+        // Without host discovery the user declared the host up
+        let reason = result.up.reason.map_or_else(|| "user-set".to_string(), |reason| reason.to_string());
+        writeln!(writer, "<host starttime=\"{}\" endtime=\"{}\">", unix_seconds(result.started), unix_seconds(result.finished))?;
+        writeln!(writer, "<status state=\"up\" reason=\"{reason}\" reason_ttl=\"0\"/>")?;
+        let address_type = match result.host.ip {
+            IpAddr::V4(_) => "ipv4",
+            IpAddr::V6(_) => "ipv6",
+        };
+        writeln!(writer, "<address addr=\"{}\" addrtype=\"{address_type}\"/>", result.host.ip)?;
+        match (result.up.mac, result.up.mac.and_then(|mac| mac.vendor())) {
+            (Some(mac), Some(vendor)) => writeln!(writer, "<address addr=\"{mac}\" addrtype=\"mac\" vendor=\"{}\"/>", escape(vendor))?,
+            (Some(mac), None) => writeln!(writer, "<address addr=\"{mac}\" addrtype=\"mac\"/>")?,
+            (None, _) => {}
+        }
+        match name {
+            Some(name) => {
+                let name_type = match name {
+                    HostName::Target(_) => "user",
+                    HostName::Reverse(_) => "PTR",
+                };
+                writeln!(writer, "<hostnames>\n<hostname name=\"{}\" type=\"{name_type}\"/>\n</hostnames>", escape(name.name()))?;
+            }
+            None => writeln!(writer, "<hostnames>\n</hostnames>")?,
+        }
+        if let Some(port_states) = &result.port_states {
+            writeln!(writer, "<ports>")?;
+            for ((protocol, port), port_status) in port_states {
+                // nmap's DTD has no state for ports that were not probed
+                let state = match port_status.state {
+                    PortState::Unreachable => "filtered",
+                    PortState::LocalError => "unknown",
+                    state => state.name(),
+                };
+                write!(writer, "<port protocol=\"{protocol}\" portid=\"{port}\"><state state=\"{state}\" reason=\"{}\" reason_ttl=\"0\"/>", port_status.reason)?;
+                if let Some(service) = service_name(*port, *protocol) {
+                    write!(writer, "<service name=\"{}\" method=\"table\" conf=\"3\"/>", escape(service))?;
+                }
+                writeln!(writer, "</port>")?;
+            }
+            writeln!(writer, "</ports>")?;
+        }
+        if let Some(rtt_stats) = result.rtt_stats {
+            writeln!(
+                writer,
+                "<times srtt=\"{}\" rttvar=\"{}\" to=\"{}\"/>",
+                rtt_stats.srtt.as_micros(),
+                rtt_stats.rttvar.as_micros(),
+                rtt_stats.timeout.as_micros(),
+            )?;
+        }
+        writeln!(writer, "</host>")
+    }
+
+    fn finish(mut self: Box<Self>, stats: &RunStats) -> io::Result<()> {
+        let writer = &mut self.writer;
+        let end_str = time_string(stats.end_time);
+        let elapsed = format!("{:.2}", stats.elapsed.as_secs_f64());
+        let summary = format!(
+            "rmap done at {end_str}; {} IP addresses ({} hosts up) scanned in {elapsed} seconds",
+            stats.hosts_total, stats.hosts_up,
+        );
+        writeln!(writer, "<runstats>")?;
+        writeln!(
+            writer,
+            "<finished time=\"{}\" timestr=\"{end_str}\" summary=\"{}\" elapsed=\"{elapsed}\" exit=\"success\"/>",
+            unix_seconds(stats.end_time),
+            escape(&summary),
+        )?;
+        let down = stats.hosts_total.saturating_sub(u128::from(stats.hosts_up));
+        writeln!(writer, "<hosts up=\"{}\" down=\"{down}\" total=\"{}\"/>", stats.hosts_up, stats.hosts_total)?;
+        writeln!(writer, "</runstats>")?;
+        writeln!(writer, "</nmaprun>")?;
+        writer.flush()
+    }
+}
+
+/// Escape text for attribute values. Control characters are not allowed in
+/// XML 1.0 and left out.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::discovery::HostUp;
+    use crate::mac::MacAddr;
+    use crate::output::fixtures::{host_result, run_stats, scan_info};
+    use crate::rtt::RttStats;
+    use crate::scan::{PortStatus, Reason};
+    use crate::services::Protocol;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a&b<c>\"d'\n\u{1}"), "a&amp;b&lt;c&gt;&quot;d&apos;&#10;");
+    }
+
+    #[test]
+    fn test_xml_document() {
+        let scan = ScanInfo {
+            arguments: vec!["rmap".to_string(), "-sS".to_string(), "-sU".to_string(), "--exclude".to_string(), "<x>".to_string()],
+            scan_types: vec!["syn", "udp"],
+            ports: vec![(Protocol::Tcp, 22), (Protocol::Tcp, 23), (Protocol::Udp, 53)],
+            ..scan_info()
+        };
+        let port_states = vec![
+            ((Protocol::Tcp, 22), PortStatus::new(PortState::Open, Reason::SynAck)),
+            ((Protocol::Tcp, 23), PortStatus::new(PortState::Unreachable, Reason::HostUnreach)),
+            ((Protocol::Udp, 53), PortStatus::new(PortState::OpenFiltered, Reason::NoResponse)),
+        ];
+        let result = HostResult {
+            up: HostUp { mac: Some(MacAddr([0x02, 0, 0, 0, 0, 1])), rtt: Some(Duration::from_millis(1)), reason: Some(Reason::ArpResponse) },
+            port_states: Some(port_states.into_iter().collect()),
+            rtt_stats: Some(RttStats { srtt: Duration::from_micros(1500), rttvar: Duration::from_micros(500), timeout: Duration::from_millis(100) }),
+            started: UNIX_EPOCH + Duration::from_secs(1000),
+            ..host_result()
+        };
+        let stats = RunStats { hosts_total: 256, ..run_stats() };
+
+        let mut output = vec![];
+        let mut xml = XmlWriter::new(&mut output, &scan).unwrap();
+        xml.write_host(&result, Some(&HostName::Reverse("gw.example".to_string()))).unwrap();
+        Box::new(xml).finish(&stats).unwrap();
+        let xml = String::from_utf8(output).unwrap();
+
+        assert!(xml.contains("<!-- rmap 0.1.0 scan initiated"), "{}", xml);
+        assert!(xml.contains("as: rmap -sS -sU - -exclude <x> -->"), "{}", xml);
+        assert!(xml.contains("args=\"rmap -sS -sU --exclude &lt;x&gt;\" start=\"1000\""), "{}", xml);
+        assert!(xml.contains("<scaninfo type=\"syn\" protocol=\"tcp\" numservices=\"2\" services=\"22-23\"/>"), "{}", xml);
+        assert!(xml.contains("<scaninfo type=\"udp\" protocol=\"udp\" numservices=\"1\" services=\"53\"/>"), "{}", xml);
+        assert!(xml.contains("<host starttime=\"1000\" endtime=\"1001\">\n<status state=\"up\" reason=\"arp-response\" reason_ttl=\"0\"/>\n\
+            <address addr=\"192.0.2.1\" addrtype=\"ipv4\"/>\n<address addr=\"02:00:00:00:00:01\" addrtype=\"mac\"/>\n"), "{}", xml);
+        assert!(xml.contains("<hostname name=\"gw.example\" type=\"PTR\"/>"), "{}", xml);
+        let result = HostResult { up: HostUp { reason: Some(Reason::EchoReply), ..HostUp::default() }, ..result };
+        let mut output = vec![];
+        XmlWriter::new(&mut output, &scan).unwrap().write_host(&result, None).unwrap();
+        assert!(String::from_utf8(output).unwrap().contains("<status state=\"up\" reason=\"echo-reply\" reason_ttl=\"0\"/>"));
+        assert!(xml.contains("<port protocol=\"tcp\" portid=\"22\"><state state=\"open\" reason=\"syn-ack\" reason_ttl=\"0\"/>\
+            <service name=\"ssh\" method=\"table\" conf=\"3\"/></port>"), "{}", xml);
+        assert!(xml.contains("portid=\"23\"><state state=\"filtered\" reason=\"host-unreach\""), "{}", xml);
+        assert!(xml.contains("portid=\"53\"><state state=\"open|filtered\" reason=\"no-response\""), "{}", xml);
+        assert!(xml.contains("<times srtt=\"1500\" rttvar=\"500\" to=\"100000\"/>"), "{}", xml);
+        assert!(xml.contains("elapsed=\"2.00\" exit=\"success\"/>\n<hosts up=\"1\" down=\"255\" total=\"256\"/>\n</runstats>\n</nmaprun>\n"), "{}", xml);
+    }
+}